@@ -1,46 +1,392 @@
-use chrono;
+use async_compression::tokio::write::GzipEncoder;
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use futures::future;
+use futures::stream::{self, Stream, StreamExt};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{Semaphore, broadcast, mpsc, watch};
+use tokio::task::JoinSet;
 use tokio::time::sleep;
+use unicode_normalization::UnicodeNormalization;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub name: String,
-    pub email: String,
-    pub age: u8,
+    /// `None` for users without an email; only allowed when the service is
+    /// built with `email_required(false)`.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Read from a number or a numeric string, like `CreateUserRequest::age`.
+    #[serde(default, deserialize_with = "deserialize_age")]
+    pub age: Option<u8>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Previous emails, oldest first, each with the time it was replaced.
+    #[serde(default)]
+    pub email_history: Vec<(String, chrono::DateTime<chrono::Utc>)>,
+    /// Monotonic creation counter, used to break `created_at` ties so that
+    /// creation order is total.
+    #[serde(default)]
+    pub sequence: u64,
+    /// When each field last changed value; `None` until the first change.
+    #[serde(default)]
+    pub name_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub email_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub age_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Flat CSV row for a user. Nested fields such as `email_history` have no
+/// CSV representation and are left out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserCsvRecord {
+    id: String,
+    name: String,
+    email: Option<String>,
+    age: Option<u8>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl UserCsvRecord {
+    /// Names of the fields that differ between two records of the same id.
+    fn changed_fields(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.name != other.name {
+            changed.push("name");
+        }
+        if self.email != other.email {
+            changed.push("email");
+        }
+        if self.age != other.age {
+            changed.push("age");
+        }
+        if self.created_at != other.created_at {
+            changed.push("created_at");
+        }
+        if self.updated_at != other.updated_at {
+            changed.push("updated_at");
+        }
+        changed
+    }
+}
+
+impl From<&User> for UserCsvRecord {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id.clone(),
+            name: user.name.clone(),
+            email: user.email.clone(),
+            age: user.age,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUserRequest {
     pub name: String,
-    pub email: String,
-    pub age: u8,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// `None` when the age is unknown; validation only applies when present.
+    /// Accepts a number or a numeric string.
+    #[serde(default, deserialize_with = "deserialize_age")]
+    pub age: Option<u8>,
+    /// Retries carrying the same key within the TTL return the user created
+    /// by the first call instead of creating another.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Country code from the signup address, used to pick the minimum age.
+    /// Only checked at creation; it is not stored on the user.
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+impl CreateUserRequest {
+    /// Rough heap plus inline footprint, used for memory budgeting.
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.name.len() + self.email.as_ref().map_or(0, String::len)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateUserRequest {
     pub name: Option<String>,
     pub email: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_age")]
     pub age: Option<u8>,
 }
 
-type Database = Arc<DashMap<String, User>>;
+/// Reads an optional age given either as a number or as a numeric string,
+/// since loosely typed clients often send `"30"`.
+fn deserialize_age<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Age {
+        Number(u64),
+        Text(String),
+    }
+
+    let invalid = |age: &dyn std::fmt::Display| {
+        serde::de::Error::custom(format!("age must be a whole number up to 255, got {}", age))
+    };
+    match Option::<Age>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Age::Number(age)) => u8::try_from(age).map(Some).map_err(|_| invalid(&age)),
+        Some(Age::Text(text)) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid(&format_args!("{:?}", text))),
+    }
+}
+
+/// A `get_user_or_load` call's claim on the per-id load lock. Dropping the
+/// last claim removes the lock from the in-flight map, so a cancelled loader
+/// does not leave a stale entry behind. While other callers still hold a
+/// claim the entry stays, and a caller arriving after a failed load queues on
+/// the same lock instead of starting a second loader beside the next waiter.
+struct InFlightLoad<'a> {
+    loads: &'a DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    id: &'a str,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for InFlightLoad<'_> {
+    fn drop(&mut self) {
+        // Two references are the map's and this claim's. The shard lock held
+        // by `remove_if` keeps new callers from cloning it during the check.
+        self.loads.remove_if(self.id, |_, in_flight| {
+            Arc::ptr_eq(in_flight, &self.lock) && Arc::strong_count(in_flight) == 2
+        });
+    }
+}
+
+/// Recent `search_users_with` results, most recently used first. Entries
+/// remember the service generation they were computed at and are only
+/// served while it is unchanged and their TTL has not run out.
+struct SearchCache {
+    capacity: usize,
+    ttl: chrono::Duration,
+    entries: Mutex<VecDeque<CachedSearch>>,
+}
+
+struct CachedSearch {
+    query: String,
+    fold_diacritics: bool,
+    generation: u64,
+    cached_at: chrono::DateTime<chrono::Utc>,
+    users: Vec<User>,
+}
+
+impl SearchCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl: chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn get(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        generation: u64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<Vec<User>> {
+        let mut entries = self.entries.lock().unwrap();
+        let pos = entries
+            .iter()
+            .position(|e| e.query == query && e.fold_diacritics == options.fold_diacritics)?;
+        let entry = entries.remove(pos)?;
+        if entry.generation != generation || now - entry.cached_at > self.ttl {
+            return None;
+        }
+        let users = entry.users.clone();
+        entries.push_front(entry);
+        Some(users)
+    }
+
+    fn put(&self, entry: CachedSearch) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.query != entry.query || e.fold_diacritics != entry.fold_diacritics);
+        entries.truncate(self.capacity - 1);
+        entries.push_front(entry);
+    }
+}
+
+/// The user table and the indexes derived from it. `replace_dataset` swaps
+/// all of them as one unit, so a reader that loaded them sees either the old
+/// dataset or the new one, never a mix.
+struct Tables {
+    db: DashMap<String, User, TableHasher>,
+    email_index: DashMap<String, String, TableHasher>,
+    name_index: Option<DashMap<String, Vec<String>, TableHasher>>,
+}
+
+impl Tables {
+    /// `hasher` only applies to `db`. The indexes are keyed by emails and
+    /// names that clients choose, so they always use SipHash.
+    fn new(shard_amount: Option<usize>, hasher: MapHasher, name_index: bool) -> Self {
+        Self {
+            db: new_map(shard_amount, hasher),
+            email_index: new_map(shard_amount, MapHasher::SipHash),
+            name_index: name_index.then(|| new_map(shard_amount, MapHasher::SipHash)),
+        }
+    }
+}
+
+/// Hash function for the id-keyed user table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MapHasher {
+    /// The standard library's randomly keyed SipHash, resistant to
+    /// collision attacks on keys chosen by clients.
+    #[default]
+    SipHash,
+    /// FxHash: several times faster on short string keys, but predictable.
+    /// Only for maps whose keys clients cannot pick, like generated ids, so
+    /// avoid it when restores accept ids from untrusted files.
+    Fx,
+}
+
+/// `BuildHasher` for `MapHasher`, chosen at runtime so the table type stays
+/// the same whichever hasher is configured.
+#[derive(Clone)]
+struct TableHasher {
+    kind: MapHasher,
+    sip: std::hash::RandomState,
+}
+
+impl TableHasher {
+    fn new(kind: MapHasher) -> Self {
+        Self {
+            kind,
+            sip: std::hash::RandomState::new(),
+        }
+    }
+}
+
+impl std::hash::BuildHasher for TableHasher {
+    type Hasher = TableHasherState;
+
+    fn build_hasher(&self) -> TableHasherState {
+        match self.kind {
+            MapHasher::SipHash => TableHasherState::Sip(self.sip.build_hasher()),
+            MapHasher::Fx => TableHasherState::Fx(rustc_hash::FxHasher::default()),
+        }
+    }
+}
+
+enum TableHasherState {
+    Sip(std::hash::DefaultHasher),
+    Fx(rustc_hash::FxHasher),
+}
+
+impl std::hash::Hasher for TableHasherState {
+    fn finish(&self) -> u64 {
+        match self {
+            TableHasherState::Sip(hasher) => hasher.finish(),
+            TableHasherState::Fx(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            TableHasherState::Sip(hasher) => hasher.write(bytes),
+            TableHasherState::Fx(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        match self {
+            TableHasherState::Sip(hasher) => hasher.write_u8(i),
+            TableHasherState::Fx(hasher) => hasher.write_u8(i),
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        match self {
+            TableHasherState::Sip(hasher) => hasher.write_usize(i),
+            TableHasherState::Fx(hasher) => hasher.write_usize(i),
+        }
+    }
+}
+
+const BULK_BATCH_SIZE: usize = 5000;
+const VALIDATION_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_BULK_SIZE: usize = 20_000_000;
+const DEFAULT_RECENT_OPS_CAPACITY: usize = 1024;
+const DEFAULT_EMAIL_HISTORY_LIMIT: usize = 10;
+const PARALLEL_HISTORY_CAPACITY: usize = 256;
+const UPSERT_LOCK_STRIPES: usize = 64;
+const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const INGEST_CHANNEL_CAPACITY: usize = 1024;
+const SEARCH_STREAM_CAPACITY: usize = 1024;
+const DEFAULT_SNAPSHOT_RETENTION: usize = 5;
+const EVENT_BROADCAST_CAPACITY: usize = 1024;
+const EVENT_LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_CONCURRENT_BULK: usize = 4;
+const STATS_HISTORY_CAPACITY: usize = 1024;
+const DEFAULT_MIN_AGE: u8 = 13;
+const MAX_AGE: u8 = 120;
 
 #[derive(Debug)]
 pub enum DatabaseError {
     UserNotFound,
     UserAlreadyExists,
     ValidationError(String),
+    InputTooLarge {
+        size: usize,
+        max: usize,
+    },
+    TaskPanicked(String),
+    TaskCancelled,
+    Timeout,
+    /// Another request in the same bulk call has this email; `kept` is the
+    /// index of the request that was inserted instead.
+    DuplicateInBatch {
+        kept: usize,
+    },
+    /// Produced by a `FaultInjector`, never by real operations.
+    Injected,
+    /// A transactional bulk call inserted nothing; each entry is a request
+    /// index and why it failed.
+    BatchRejected {
+        violations: Vec<(usize, String)>,
+    },
+    /// A file write that still failed after every attempt of its
+    /// `RetryPolicy`.
+    Io(std::io::Error),
+}
+
+impl From<tokio::task::JoinError> for DatabaseError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        if err.is_cancelled() {
+            return DatabaseError::TaskCancelled;
+        }
+        let payload = err.into_panic();
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic payload".to_string());
+        DatabaseError::TaskPanicked(msg)
+    }
 }
 
 impl std::fmt::Display for DatabaseError {
@@ -49,487 +395,8742 @@ impl std::fmt::Display for DatabaseError {
             DatabaseError::UserNotFound => write!(f, "User not found"),
             DatabaseError::UserAlreadyExists => write!(f, "User already exists"),
             DatabaseError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            DatabaseError::InputTooLarge { size, max } => {
+                write!(f, "Input too large: {} items (max {})", size, max)
+            }
+            DatabaseError::TaskPanicked(msg) => write!(f, "Task panicked: {}", msg),
+            DatabaseError::TaskCancelled => write!(f, "Task was cancelled"),
+            DatabaseError::Timeout => write!(f, "Timed out"),
+            DatabaseError::Injected => write!(f, "Injected fault"),
+            DatabaseError::DuplicateInBatch { kept } => {
+                write!(f, "Duplicate email in batch (request #{} kept)", kept)
+            }
+            DatabaseError::Io(e) => write!(f, "I/O error: {}", e),
+            DatabaseError::BatchRejected { violations } => {
+                let listed: Vec<String> = violations
+                    .iter()
+                    .map(|(i, msg)| format!("#{}: {}", i, msg))
+                    .collect();
+                write!(f, "Batch rejected: {}", listed.join("; "))
+            }
         }
     }
 }
 
-impl std::error::Error for DatabaseError {}
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DatabaseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct ServiceStats {
+    /// Every recorded attempt, successful or not; subtract the `*_failed`
+    /// counters to get the number of successes.
     pub total_operations: u64,
     pub create_count: u64,
     pub read_count: u64,
     pub update_count: u64,
     pub delete_count: u64,
     pub parallel_operations: u64,
+    pub create_failed: u64,
+    pub update_failed: u64,
+    pub delete_failed: u64,
+    pub validation_failed: u64,
 }
 
-pub struct UserService {
-    db: Database,
-    stats: Arc<DashMap<(), ServiceStats>>,
-}
-
-impl UserService {
-    pub fn new() -> Self {
-        Self {
-            db: Arc::new(DashMap::new()),
-            stats: Arc::new(DashMap::new()),
+impl ServiceStats {
+    /// Field-wise `self - earlier`, saturating at zero.
+    pub fn saturating_sub(&self, earlier: &ServiceStats) -> ServiceStats {
+        ServiceStats {
+            total_operations: self
+                .total_operations
+                .saturating_sub(earlier.total_operations),
+            create_count: self.create_count.saturating_sub(earlier.create_count),
+            read_count: self.read_count.saturating_sub(earlier.read_count),
+            update_count: self.update_count.saturating_sub(earlier.update_count),
+            delete_count: self.delete_count.saturating_sub(earlier.delete_count),
+            parallel_operations: self
+                .parallel_operations
+                .saturating_sub(earlier.parallel_operations),
+            create_failed: self.create_failed.saturating_sub(earlier.create_failed),
+            update_failed: self.update_failed.saturating_sub(earlier.update_failed),
+            delete_failed: self.delete_failed.saturating_sub(earlier.delete_failed),
+            validation_failed: self
+                .validation_failed
+                .saturating_sub(earlier.validation_failed),
         }
     }
 
-    pub async fn create_user(&self, req: CreateUserRequest) -> Result<User, DatabaseError> {
-        self.validate_user_data(&req).await?;
-        let user = User {
-            id: Uuid::new_v4().to_string(),
-            name: req.name,
-            email: req.email.to_lowercase(),
-            age: req.age,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
 
-        if self.db.contains_key(&user.id) {
-            return Err(DatabaseError::UserAlreadyExists);
+/// One line by default; the alternate form (`{:#}`) spreads the counters over
+/// several lines for reports.
+impl std::fmt::Display for ServiceStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if f.alternate() {
+            writeln!(f, "Total ops: {}", self.total_operations)?;
+            writeln!(
+                f,
+                "Creates: {}, Reads: {}, Updates: {}, Deletes: {}",
+                self.create_count, self.read_count, self.update_count, self.delete_count
+            )?;
+            writeln!(f, "Parallel batches: {}", self.parallel_operations)?;
+            write!(
+                f,
+                "Failed creates: {} (validation: {}), updates: {}, deletes: {}",
+                self.create_failed, self.validation_failed, self.update_failed, self.delete_failed
+            )
+        } else {
+            write!(
+                f,
+                "ops={} creates={} reads={} updates={} deletes={} parallel={} \
+                 failed_creates={} failed_validations={} failed_updates={} failed_deletes={}",
+                self.total_operations,
+                self.create_count,
+                self.read_count,
+                self.update_count,
+                self.delete_count,
+                self.parallel_operations,
+                self.create_failed,
+                self.validation_failed,
+                self.update_failed,
+                self.delete_failed
+            )
         }
-
-        self.db.insert(user.id.clone(), user.clone());
-        self.increment_stat(|stats| stats.create_count += 1).await;
-        Ok(user)
     }
+}
 
-    pub async fn get_user(&self, id: &str) -> Result<User, DatabaseError> {
-        match self.db.get(id) {
-            Some(user) => {
-                self.increment_stat(|stats| stats.read_count += 1).await;
-                Ok(user.value().clone())
-            }
-            None => Err(DatabaseError::UserNotFound),
+/// Makes `create_user` and `get_user` fail with `DatabaseError::Injected`,
+/// for exercising retry paths. Random failures come from a seeded generator,
+/// so the same seed and call order fail the same calls.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    probability: f64,
+    ids: HashSet<String>,
+    state: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+            ..Self::default()
         }
     }
 
-    pub async fn update_user(
-        &self,
-        id: &str,
-        req: UpdateUserRequest,
-    ) -> Result<User, DatabaseError> {
-        {
-            let mut user = match self.db.get_mut(id) {
-                Some(u) => u,
-                None => return Err(DatabaseError::UserNotFound),
-            };
-            if let Some(name) = req.name {
-                user.name = name;
-            }
-            if let Some(email) = req.email {
-                user.email = email.to_lowercase();
-            }
-            if let Some(age) = req.age {
-                user.age = age;
-            }
-            user.updated_at = chrono::Utc::now();
-        }
-        self.increment_stat(|stats| stats.update_count += 1).await;
-        self.get_user(id).await
+    /// Fail this fraction of calls, from 0.0 (never) to 1.0 (always).
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability.clamp(0.0, 1.0);
+        self
     }
 
-    pub async fn delete_user(&self, id: &str) -> Result<User, DatabaseError> {
-        match self.db.remove(id) {
-            Some((_, user)) => {
-                self.increment_stat(|stats| stats.delete_count += 1).await;
-                Ok(user)
-            }
-            None => Err(DatabaseError::UserNotFound),
-        }
+    /// Always fail `get_user` for this id.
+    pub fn fail_id(mut self, id: impl Into<String>) -> Self {
+        self.ids.insert(id.into());
+        self
     }
 
-    pub async fn list_users(&self) -> Result<Vec<User>, DatabaseError> {
-        let users = self.db.iter().map(|kv| kv.value().clone()).collect();
-        self.increment_stat(|stats| stats.read_count += 1).await;
-        Ok(users)
+    fn should_fail(&self, id: Option<&str>) -> bool {
+        if id.is_some_and(|id| self.ids.contains(id)) {
+            return true;
+        }
+        if self.probability <= 0.0 {
+            return false;
+        }
+        // splitmix64
+        let mut z = self
+            .state
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < self.probability
     }
+}
 
-    pub async fn bulk_create_users(
-        self: Arc<Self>,
-        requests: Vec<CreateUserRequest>,
-    ) -> Vec<Result<User, DatabaseError>> {
-        println!(
-            "🎯 [Rayon] Transforming {} requests in parallel (to_uppercase)...",
-            requests.len()
-        );
+pub type InvalidateHook = Box<dyn Fn(&str) + Send + Sync>;
 
-        let processed: Vec<_> = requests
-            .into_par_iter()
-            .map(|req| CreateUserRequest {
-                name: req.name.to_uppercase(),
-                email: req.email,
-                age: req.age,
-            })
-            .collect();
+/// One CRUD call as reported to an `on_operation` hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationMetric {
+    pub kind: OpKind,
+    pub success: bool,
+    pub duration: Duration,
+}
 
-        println!("✅ [Rayon] Transformation done.");
+pub type OperationHook = Box<dyn Fn(OperationMetric) + Send + Sync>;
 
-        let mut results = Vec::with_capacity(processed.len());
-        const BATCH_SIZE: usize = 5000;
+pub trait Clock: Send + Sync {
+    fn now(&self) -> chrono::DateTime<chrono::Utc>;
+}
 
-        for (i, batch) in processed.chunks(BATCH_SIZE).enumerate() {
-            println!(
-                "🚀 [Tokio] Spawning async tasks for batch #{} ({} users)...",
-                i + 1,
-                batch.len()
-            );
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
 
-            let tasks = batch.iter().cloned().map(|req| {
-                let svc = Arc::clone(&self);
-                tokio::spawn(async move {
-                    println!("⚙️ [Tokio] Creating user: {}", req.name);
-                    svc.create_user(req).await
-                })
-            });
+impl Clock for SystemClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+    }
+}
 
-            let batch_results = futures::future::join_all(tasks).await;
-            println!("✅ [Tokio] Batch #{} finished.", i + 1);
+/// A clock that only moves when told to, for deterministic timestamps.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<chrono::DateTime<chrono::Utc>>,
+}
 
-            results.extend(
-                batch_results.into_iter().map(|r| {
-                    r.unwrap_or_else(|e| Err(DatabaseError::ValidationError(e.to_string())))
-                }),
-            );
+impl MockClock {
+    pub fn new(start: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            now: Mutex::new(start),
         }
+    }
 
-        self.increment_stat(|stats| stats.parallel_operations += 1)
-            .await;
+    pub fn set(&self, now: chrono::DateTime<chrono::Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
 
-        println!(
-            "📊 [Stat] Total batches processed: {}",
-            (processed.len() + BATCH_SIZE - 1) / BATCH_SIZE
-        );
-        results
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
     }
+}
 
-    pub async fn search_users_parallel(&self, query: &str) -> Result<Vec<User>, DatabaseError> {
-        let users = self.list_users().await?;
-        let query = query.to_lowercase();
-        let results: Vec<User> = users
-            .into_par_iter()
-            .filter(|user| {
-                user.name.to_lowercase().contains(&query)
-                    || user.email.to_lowercase().contains(&query)
-            })
-            .collect();
-        Ok(results)
+impl Clock for MockClock {
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        *self.now.lock().unwrap()
     }
+}
 
-    pub async fn fast_concurrent_operations(self: Arc<Self>) -> Result<(), DatabaseError> {
-        println!("🚀 Running 5 FAST concurrent operations...");
-        let start = Instant::now();
+/// Progress notifications emitted by the long-running service methods.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    TransformStarted {
+        count: usize,
+    },
+    TransformFinished,
+    BatchStarted {
+        batch: usize,
+        size: usize,
+    },
+    CreatingUser {
+        name: String,
+    },
+    BatchFinished {
+        batch: usize,
+    },
+    BulkFinished {
+        batches: usize,
+    },
+    FastOpsStarted {
+        count: usize,
+    },
+    FastTaskFinished {
+        task: usize,
+        created: Option<String>,
+    },
+    FastOpsFinished {
+        duration: Duration,
+    },
+    BulkInsertStarted {
+        count: usize,
+    },
+    BulkInsertFinished {
+        inserted: usize,
+        duration: Duration,
+    },
+    Saved {
+        format: Format,
+        count: usize,
+        path: String,
+        duration: Duration,
+        serialize: Duration,
+        write: Duration,
+    },
+    LoadStarted {
+        format: Format,
+        count: usize,
+        path: String,
+        read: Duration,
+        parse: Duration,
+    },
+    Loaded {
+        format: Format,
+        count: usize,
+        path: String,
+        duration: Duration,
+        insert: Duration,
+    },
+    Retry {
+        attempt: u32,
+    },
+    ShardedCsvSaved {
+        dir: String,
+        counts: Vec<usize>,
+        duration: Duration,
+    },
+    DuplicateEmailsFound {
+        path: String,
+        count: usize,
+        policy: DuplicateEmailPolicy,
+    },
+    StreamSaved {
+        path: String,
+        rows: usize,
+        bytes_written: u64,
+        duration: Duration,
+    },
+    MigrationSkipped {
+        id: String,
+        reason: String,
+    },
+    /// A `merge_import_ndjson_stream` line that was not merged; `line` is
+    /// 1-based and counts blank lines.
+    MergeLineFailed {
+        line: usize,
+        reason: String,
+    },
+    ThrottledFinished {
+        created: usize,
+        failed: usize,
+        duration: Duration,
+        achieved_per_sec: f64,
+    },
+}
 
-        let reqs = (0..5)
-            .map(|i| CreateUserRequest {
-                name: format!("Fast User {}", i),
-                email: format!("fast{}@demo.com", i),
-                age: 20 + i as u8,
-            })
-            .collect::<Vec<_>>();
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
 
-        let handles = reqs.into_iter().map(|req| {
-            let service = Arc::clone(&self);
-            async move { service.create_user(req).await }
-        });
+/// Prints every event to stdout in the demo's emoji format.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConsoleReporter;
 
-        let results = future::join_all(handles).await;
+impl Reporter for ConsoleReporter {
+    fn report(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::TransformStarted { count } => println!(
+                "🎯 [Rayon] Transforming {} requests in parallel (to_uppercase)...",
+                count
+            ),
+            ProgressEvent::TransformFinished => println!("✅ [Rayon] Transformation done."),
+            ProgressEvent::BatchStarted { batch, size } => println!(
+                "🚀 [Tokio] Spawning async tasks for batch #{} ({} users)...",
+                batch, size
+            ),
+            ProgressEvent::CreatingUser { name } => println!("⚙️ [Tokio] Creating user: {}", name),
+            ProgressEvent::BatchFinished { batch } => {
+                println!("✅ [Tokio] Batch #{} finished.", batch)
+            }
+            ProgressEvent::BulkFinished { batches } => {
+                println!("📊 [Stat] Total batches processed: {}", batches)
+            }
+            ProgressEvent::FastOpsStarted { count } => {
+                println!("🚀 Running {} FAST concurrent operations...", count)
+            }
+            ProgressEvent::FastTaskFinished { task, created } => match created {
+                Some(name) => println!("✅ Fast Task {}: Created {}", task, name),
+                None => println!("❌ Fast Task {}: Failed", task),
+            },
+            ProgressEvent::FastOpsFinished { duration } => {
+                println!("✅ Fast concurrent ops done in {:?}", duration)
+            }
+            ProgressEvent::BulkInsertStarted { count } => {
+                println!("🚀 Bulk insert {} users with rayon + concurrent", count)
+            }
+            ProgressEvent::BulkInsertFinished { inserted, duration } => {
+                println!("✅ Inserted {} users in {:?}", inserted, duration)
+            }
+            ProgressEvent::Saved {
+                count,
+                path,
+                duration,
+                serialize,
+                write,
+                ..
+            } => println!(
+                "✅ Saved {} users to {} in {:?} (serialize: {:?}, write: {:?})",
+                count, path, duration, serialize, write
+            ),
+            ProgressEvent::LoadStarted {
+                count,
+                path,
+                read,
+                parse,
+                ..
+            } => println!(
+                "🚀 Loading {} users from {}... (read: {:?}, parse: {:?})",
+                count, path, read, parse
+            ),
+            ProgressEvent::Loaded {
+                count,
+                path,
+                duration,
+                insert,
+                ..
+            } => println!(
+                "✅ Loaded {} users from {} in {:?} (insert: {:?})",
+                count, path, duration, insert
+            ),
+            ProgressEvent::Retry { attempt } => {
+                println!("🔁 Retry attempt {} due to temporary issue", attempt)
+            }
+            ProgressEvent::ShardedCsvSaved {
+                dir,
+                counts,
+                duration,
+            } => println!(
+                "✅ Saved {} users across {} shards in {} in {:?} (per shard: {:?})",
+                counts.iter().sum::<usize>(),
+                counts.len(),
+                dir,
+                duration,
+                counts
+            ),
+            ProgressEvent::DuplicateEmailsFound {
+                path,
+                count,
+                policy,
+            } => println!(
+                "⚠️ {} duplicate emails in {} (policy: {:?})",
+                count, path, policy
+            ),
+            ProgressEvent::StreamSaved {
+                path,
+                rows,
+                bytes_written,
+                duration,
+            } => println!(
+                "✅ Streamed {} users to {} ({} bytes) in {:?}",
+                rows, path, bytes_written, duration
+            ),
+            ProgressEvent::MigrationSkipped { id, reason } => {
+                println!("⚠️ Migration skipped user {}: {}", id, reason)
+            }
+            ProgressEvent::MergeLineFailed { line, reason } => {
+                println!("⚠️ Merge import failed line {}: {}", line, reason)
+            }
+            ProgressEvent::ThrottledFinished {
+                created,
+                failed,
+                duration,
+                achieved_per_sec,
+            } => println!(
+                "🐢 Throttled insert: {} created, {} failed in {:?} ({:.1}/s)",
+                created, failed, duration, achieved_per_sec
+            ),
+        }
+    }
+}
 
-        let duration = start.elapsed();
-        for (i, result) in results.into_iter().enumerate() {
-            match result {
-                Ok(user) => println!("✅ Fast Task {}: Created {}", i, user.name),
-                Err(_) => println!("❌ Fast Task {}: Failed", i),
+impl ProgressEvent {
+    /// The event's name in snake case, with the item count and duration it
+    /// carries, if any. Used for structured output.
+    fn summary(&self) -> (&'static str, Option<usize>, Option<Duration>) {
+        match self {
+            ProgressEvent::TransformStarted { count } => ("transform_started", Some(*count), None),
+            ProgressEvent::TransformFinished => ("transform_finished", None, None),
+            ProgressEvent::BatchStarted { size, .. } => ("batch_started", Some(*size), None),
+            ProgressEvent::CreatingUser { .. } => ("creating_user", Some(1), None),
+            ProgressEvent::BatchFinished { .. } => ("batch_finished", None, None),
+            ProgressEvent::BulkFinished { batches } => ("bulk_finished", Some(*batches), None),
+            ProgressEvent::FastOpsStarted { count } => ("fast_ops_started", Some(*count), None),
+            ProgressEvent::FastTaskFinished { created, .. } => (
+                "fast_task_finished",
+                Some(usize::from(created.is_some())),
+                None,
+            ),
+            ProgressEvent::FastOpsFinished { duration } => {
+                ("fast_ops_finished", None, Some(*duration))
+            }
+            ProgressEvent::BulkInsertStarted { count } => {
+                ("bulk_insert_started", Some(*count), None)
+            }
+            ProgressEvent::BulkInsertFinished { inserted, duration } => {
+                ("bulk_insert_finished", Some(*inserted), Some(*duration))
+            }
+            ProgressEvent::Saved {
+                count, duration, ..
+            } => ("saved", Some(*count), Some(*duration)),
+            ProgressEvent::LoadStarted { count, .. } => ("load_started", Some(*count), None),
+            ProgressEvent::Loaded {
+                count, duration, ..
+            } => ("loaded", Some(*count), Some(*duration)),
+            ProgressEvent::Retry { .. } => ("retry", None, None),
+            ProgressEvent::ShardedCsvSaved {
+                counts, duration, ..
+            } => (
+                "sharded_csv_saved",
+                Some(counts.iter().sum()),
+                Some(*duration),
+            ),
+            ProgressEvent::DuplicateEmailsFound { count, .. } => {
+                ("duplicate_emails_found", Some(*count), None)
+            }
+            ProgressEvent::StreamSaved { rows, duration, .. } => {
+                ("stream_saved", Some(*rows), Some(*duration))
             }
+            ProgressEvent::MigrationSkipped { .. } => ("migration_skipped", Some(1), None),
+            ProgressEvent::MergeLineFailed { .. } => ("merge_line_failed", Some(1), None),
+            ProgressEvent::ThrottledFinished {
+                created, duration, ..
+            } => ("throttled_finished", Some(*created), Some(*duration)),
         }
-        println!("✅ Fast concurrent ops done in {:?}", duration);
-        Ok(())
     }
+}
 
-    pub async fn bulk_insert_concurrent(
-        self: Arc<Self>,
-        count: usize,
-    ) -> Result<(), DatabaseError> {
-        println!("🚀 Bulk insert {} users with rayon + concurrent", count);
-        let start = Instant::now();
+/// Prints every event to stdout as one JSON object per line, with
+/// `operation`, `count` and `duration_ms` keys; absent values are `null`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonReporter;
 
-        let requests: Vec<_> = (0..count)
-            .map(|i| CreateUserRequest {
-                name: format!("BulkConcurrent {}", i),
-                email: format!("bulk{}@demo.com", i),
-                age: 20 + (i % 80) as u8,
-            })
-            .collect();
+impl Reporter for JsonReporter {
+    fn report(&self, event: ProgressEvent) {
+        let (operation, count, duration) = event.summary();
+        print_json_line(operation, count, duration, serde_json::Map::new());
+    }
+}
 
-        let processed: Vec<_> = requests
-            .into_par_iter()
-            .map(|req| {
-                let name = req.name.to_uppercase();
-                CreateUserRequest { name, ..req }
-            })
-            .collect();
+fn print_json_line(
+    operation: &str,
+    count: Option<usize>,
+    duration: Option<Duration>,
+    mut fields: serde_json::Map<String, serde_json::Value>,
+) {
+    fields.insert("operation".to_string(), operation.into());
+    fields.insert("count".to_string(), count.into());
+    fields.insert(
+        "duration_ms".to_string(),
+        duration.map(|d| d.as_secs_f64() * 1000.0).into(),
+    );
+    println!("{}", serde_json::Value::Object(fields));
+}
 
-        let handles = processed.into_iter().map(|req| {
-            let service = Arc::clone(&self);
-            async move { service.create_user(req).await }
-        });
+/// Discards every event; for embedding the service as a library.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn report(&self, _event: ProgressEvent) {}
+}
+
+/// Keeps every event in memory so callers can inspect what was reported.
+#[derive(Debug, Default)]
+pub struct CollectingReporter {
+    events: Mutex<Vec<ProgressEvent>>,
+}
+
+impl CollectingReporter {
+    pub fn events(&self) -> Vec<ProgressEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Reporter for CollectingReporter {
+    fn report(&self, event: ProgressEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+/// A committed mutation, as delivered to event sinks and subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "user")]
+pub enum UserEvent {
+    Created(User),
+    Updated(User),
+    Deleted(User),
+}
+
+/// Receives user mutations after they are committed. A failing sink is
+/// logged and skipped; it never fails the operation that produced the event.
+#[async_trait::async_trait]
+pub trait EventSink: Send + Sync {
+    async fn handle(
+        &self,
+        event: UserEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoggingSink;
+
+#[async_trait::async_trait]
+impl EventSink for LoggingSink {
+    async fn handle(
+        &self,
+        event: UserEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match event {
+            UserEvent::Created(user) => println!("📣 [Event] Created {} ({})", user.id, user.name),
+            UserEvent::Updated(user) => println!("📣 [Event] Updated {} ({})", user.id, user.name),
+            UserEvent::Deleted(user) => println!("📣 [Event] Deleted {} ({})", user.id, user.name),
+        }
+        Ok(())
+    }
+}
+
+/// Forwards events into a channel; fails once the receiver is dropped.
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    tx: mpsc::Sender<UserEvent>,
+}
+
+impl ChannelSink {
+    pub fn new(tx: mpsc::Sender<UserEvent>) -> Self {
+        Self { tx }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for ChannelSink {
+    async fn handle(
+        &self,
+        event: UserEvent,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tx.send(event).await?;
+        Ok(())
+    }
+}
+
+/// Results of `bulk_create_users_tracked`, with the stats it moved.
+#[derive(Debug)]
+pub struct BulkCreateRun {
+    pub results: Vec<Result<User, DatabaseError>>,
+    /// Service counters after the run minus before it. The counters are
+    /// shared, so operations other callers complete during the run are
+    /// included too.
+    pub stats_delta: ServiceStats,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BulkCreateSummary {
+    pub created: usize,
+    pub failed: usize,
+}
+
+/// Users laid out as parallel arrays so repeated full scans walk contiguous
+/// memory instead of the sharded map. Row `i` of every column is one user.
+#[derive(Debug, Default, Clone)]
+pub struct ColumnarSnapshot {
+    pub ids: Vec<String>,
+    pub names: Vec<String>,
+    pub emails: Vec<Option<String>>,
+    pub ages: Vec<Option<u8>>,
+    pub created_at: Vec<chrono::DateTime<chrono::Utc>>,
+    pub updated_at: Vec<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ColumnarSnapshot {
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Mean over users with a known age.
+    pub fn average_age(&self) -> Option<f64> {
+        let (total, known) = on_rayon(|| {
+            self.ages
+                .par_iter()
+                .flatten()
+                .map(|&age| (age as u64, 1u64))
+                .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+        });
+        (known > 0).then(|| total as f64 / known as f64)
+    }
+
+    pub fn age_range(&self) -> Option<(u8, u8)> {
+        on_rayon(|| {
+            let min = self.ages.par_iter().flatten().copied().min()?;
+            let max = self.ages.par_iter().flatten().copied().max()?;
+            Some((min, max))
+        })
+    }
+
+    pub fn count_in_age_range(&self, min: u8, max: u8) -> usize {
+        on_rayon(|| {
+            self.ages
+                .par_iter()
+                .flatten()
+                .filter(|&&age| age >= min && age <= max)
+                .count()
+        })
+    }
+
+    pub fn count_by_domain(&self) -> HashMap<String, usize> {
+        on_rayon(|| {
+            self.emails
+                .par_iter()
+                .filter_map(|email| email.as_deref()?.split_once('@').map(|(_, domain)| domain))
+                .fold(HashMap::new, |mut acc, domain| {
+                    *acc.entry(domain.to_string()).or_insert(0) += 1;
+                    acc
+                })
+                .reduce(HashMap::new, |mut a, b| {
+                    for (domain, count) in b {
+                        *a.entry(domain).or_insert(0) += count;
+                    }
+                    a
+                })
+        })
+    }
+}
+
+/// Canonicalizes emails before they are stored and indexed, so that aliases
+/// of the same mailbox collide on uniqueness. Trimming and lowercasing are
+/// always applied.
+#[derive(Debug, Clone, Default)]
+pub struct EmailNormalizer {
+    /// Drop everything from `+` up to the `@` in the local part.
+    pub strip_plus_tag: bool,
+    /// Domains whose providers ignore dots in the local part.
+    pub dotless_domains: Vec<String>,
+}
+
+impl EmailNormalizer {
+    /// Plus-tag stripping plus dot removal for Gmail addresses.
+    pub fn canonical() -> Self {
+        Self {
+            strip_plus_tag: true,
+            dotless_domains: vec!["gmail.com".to_string(), "googlemail.com".to_string()],
+        }
+    }
+
+    pub fn normalize(&self, email: &str) -> String {
+        let email = email.trim().to_lowercase();
+        let Some((local, domain)) = email.rsplit_once('@') else {
+            return email;
+        };
+
+        let mut local = local;
+        if self.strip_plus_tag
+            && let Some((base, _tag)) = local.split_once('+')
+        {
+            local = base;
+        }
+        if self.dotless_domains.iter().any(|d| d == domain) {
+            format!("{}@{}", local.replace('.', ""), domain)
+        } else {
+            format!("{}@{}", local, domain)
+        }
+    }
+}
+
+/// How search queries are compared against names and emails. Matching is
+/// always a case-insensitive substring test.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOptions {
+    /// Strip accents from both sides before comparing, so "jose" matches
+    /// "José". Decomposes every compared string, so it is off by default.
+    pub fold_diacritics: bool,
+}
+
+impl SearchOptions {
+    fn normalize(self, text: &str) -> String {
+        let lower = text.to_lowercase();
+        if !self.fold_diacritics {
+            return lower;
+        }
+        lower
+            .nfd()
+            .filter(|c| !unicode_normalization::char::is_combining_mark(*c))
+            .collect()
+    }
+}
+
+/// Length limits applied to emails during validation, in bytes. The
+/// defaults follow RFC 5321.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmailLimits {
+    pub max_len: usize,
+    pub max_local_len: usize,
+    pub max_domain_len: usize,
+}
+
+impl Default for EmailLimits {
+    fn default() -> Self {
+        Self {
+            max_len: 254,
+            max_local_len: 64,
+            max_domain_len: 253,
+        }
+    }
+}
+
+/// How often, and how far apart, a failed operation is attempted again. The
+/// delay doubles after every attempt, up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first; at least one.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The wait after failed attempt number `attempt`, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpKind {
+    Create,
+    Read,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpRecord {
+    pub kind: OpKind,
+    pub id: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub success: bool,
+}
+
+/// Fixed-capacity ring of the most recent operations. Writers claim a slot
+/// with an atomic cursor and only lock that slot, so concurrent ops rarely
+/// contend.
+struct OpLog {
+    slots: Vec<Mutex<Option<(u64, OpRecord)>>>,
+    next: AtomicU64,
+}
+
+impl OpLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity).map(|_| Mutex::new(None)).collect(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, record: OpRecord) {
+        if self.slots.is_empty() {
+            return;
+        }
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = (seq % self.slots.len() as u64) as usize;
+        let mut guard = self.slots[slot].lock().unwrap();
+        // A slower writer that lost the race for this slot must not overwrite
+        // a newer record.
+        if guard.as_ref().is_none_or(|(current, _)| *current < seq) {
+            *guard = Some((seq, record));
+        }
+    }
+
+    fn recent(&self, n: usize) -> Vec<OpRecord> {
+        let mut records: Vec<_> = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.lock().unwrap().clone())
+            .collect();
+        records.sort_unstable_by_key(|(seq, _)| std::cmp::Reverse(*seq));
+        records.into_iter().take(n).map(|(_, r)| r).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParallelOpKind {
+    BulkCreate,
+    BulkCreateFromIter,
+    BulkCreateBudgeted,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParallelOpRecord {
+    pub kind: ParallelOpKind,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub duration: Duration,
+    pub item_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Email,
+    Age,
+    CreatedAt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl SortKey {
+    /// Orders by this key, then by creation sequence so that ties always
+    /// resolve the same way. Only the key is reversed for `Order::Desc`.
+    fn compare(self, order: Order, a: &User, b: &User) -> std::cmp::Ordering {
+        let primary = match self {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Email => a.email.cmp(&b.email),
+            SortKey::Age => a.age.cmp(&b.age),
+            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+        };
+        let primary = match order {
+            Order::Asc => primary,
+            Order::Desc => primary.reverse(),
+        };
+        primary
+            .then_with(|| a.sequence.cmp(&b.sequence))
+            .then_with(|| a.id.cmp(&b.id))
+    }
+}
+
+/// Heap entry ordered by a `SortKey`, so a max-heap keeps the worst of the
+/// current top N on top.
+struct Ranked {
+    user: User,
+    key: SortKey,
+    order: Order,
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.compare(self.order, &self.user, &other.user)
+    }
+}
+
+/// Quoting and escaping used by the CSV save and load paths. The defaults
+/// match the `csv` crate's own.
+#[derive(Debug, Clone, Copy)]
+pub struct CsvOptions {
+    pub quote_style: csv::QuoteStyle,
+    pub quote: u8,
+    /// Escape an embedded quote by doubling it (`""`). When false, `escape`
+    /// is written before it instead.
+    pub double_quote: bool,
+    pub escape: u8,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            quote_style: csv::QuoteStyle::Necessary,
+            quote: b'"',
+            double_quote: true,
+            escape: b'\\',
+        }
+    }
+}
+
+impl CsvOptions {
+    fn writer_builder(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .quote_style(self.quote_style)
+            .quote(self.quote)
+            .double_quote(self.double_quote)
+            .escape(self.escape);
+        builder
+    }
+
+    fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .quote(self.quote)
+            .double_quote(self.double_quote)
+            .escape((!self.double_quote).then_some(self.escape));
+        builder
+    }
+}
+
+/// Shape of the JSON and NDJSON exports.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonOptions {
+    /// Write ages as strings (`"30"`) instead of numbers, for consumers that
+    /// expect them quoted. The JSON and NDJSON loaders and
+    /// `merge_import_ndjson_stream` accept either form.
+    pub age_as_string: bool,
+}
+
+impl JsonOptions {
+    fn to_value(self, user: &User) -> Result<serde_json::Value, serde_json::Error> {
+        let mut value = serde_json::to_value(user)?;
+        if self.age_as_string
+            && let Some(age) = value.get_mut("age")
+            && age.is_number()
+        {
+            *age = serde_json::Value::String(age.to_string());
+        }
+        Ok(value)
+    }
+}
+
+/// A record read back from an export. Loading only keeps the id for
+/// reporting and assigns fresh ids; `restore_from_csv` keeps ids and
+/// timestamps.
+#[derive(Debug, Clone, Deserialize)]
+struct LoadedRow {
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_age")]
+    age: Option<u8>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    #[serde(default)]
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl LoadedRow {
+    fn into_request(self) -> CreateUserRequest {
+        CreateUserRequest {
+            name: self.name,
+            email: self.email,
+            age: self.age,
+            idempotency_key: self.idempotency_key,
+            country: None,
+        }
+    }
+}
+
+/// What to do when a loaded file repeats an email.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateEmailPolicy {
+    /// Fail the whole load without inserting anything.
+    Reject,
+    KeepFirst,
+    KeepLast,
+}
+
+/// An email that appears on more than one row, with each row's index and
+/// the id stored in the file for it, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateEmail {
+    pub email: String,
+    pub occurrences: Vec<(usize, Option<String>)>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LoadSummary {
+    pub loaded: usize,
+    pub failed: usize,
+    pub duplicate_emails: Vec<DuplicateEmail>,
+}
+
+/// Stage counts of `import_csv_clean`. `parsed` rows end up in exactly one
+/// of `deduped`, `invalid`, `inserted` or `failed`.
+#[derive(Debug, Default, Clone)]
+pub struct ImportReport {
+    pub parsed: usize,
+    /// Later rows whose email an earlier row in the file already had.
+    pub deduped: usize,
+    /// Rows rejected by validation before any insert.
+    pub invalid: usize,
+    pub inserted: usize,
+    /// Valid rows whose insert failed, e.g. on an email already stored.
+    pub failed: usize,
+}
+
+/// A user column, for exports that only need some of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Id,
+    Name,
+    Email,
+    Age,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl Field {
+    fn header(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Name => "name",
+            Field::Email => "email",
+            Field::Age => "age",
+            Field::CreatedAt => "created_at",
+            Field::UpdatedAt => "updated_at",
+        }
+    }
+
+    fn value(self, user: &User) -> String {
+        let timestamp = |t: chrono::DateTime<chrono::Utc>| {
+            t.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+        };
+        match self {
+            Field::Id => user.id.clone(),
+            Field::Name => user.name.clone(),
+            Field::Email => user.email.clone().unwrap_or_default(),
+            Field::Age => user.age.map(|age| age.to_string()).unwrap_or_default(),
+            Field::CreatedAt => timestamp(user.created_at),
+            Field::UpdatedAt => timestamp(user.updated_at),
+        }
+    }
+
+    const ALL: [Field; 6] = [
+        Field::Id,
+        Field::Name,
+        Field::Email,
+        Field::Age,
+        Field::CreatedAt,
+        Field::UpdatedAt,
+    ];
+
+    /// Parses a comma-separated field list such as a `?fields=name,email`
+    /// query value. Every unknown name is listed in one `ValidationError`,
+    /// which handlers should answer with 400.
+    pub fn parse_list(list: &str) -> Result<Vec<Field>, DatabaseError> {
+        let mut fields = Vec::new();
+        let mut unknown = Vec::new();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match Field::ALL.into_iter().find(|field| field.header() == name) {
+                Some(field) if !fields.contains(&field) => fields.push(field),
+                Some(_) => {}
+                None => unknown.push(name),
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(DatabaseError::ValidationError(format!(
+                "Unknown field(s): {}",
+                unknown.join(", ")
+            )));
+        }
+        if fields.is_empty() {
+            return Err(DatabaseError::ValidationError(
+                "Field list is empty".to_string(),
+            ));
+        }
+        Ok(fields)
+    }
+
+    /// Like `value`, but keeps the JSON type: ages are numbers and missing
+    /// values are `null`.
+    fn json_value(self, user: &User) -> serde_json::Value {
+        match self {
+            Field::Email => serde_json::json!(user.email),
+            Field::Age => serde_json::json!(user.age),
+            Field::CreatedAt => serde_json::json!(user.created_at),
+            Field::UpdatedAt => serde_json::json!(user.updated_at),
+            Field::Id | Field::Name => serde_json::Value::String(self.value(user)),
+        }
+    }
+}
+
+/// Which ids `restore_from_csv` and the load methods accept from a file.
+#[derive(Debug, Clone, Default)]
+pub enum IdFormat {
+    /// Any non-empty id.
+    #[default]
+    Any,
+    /// A UUID, optionally of one version only (4 for random ids), after the
+    /// service's `id_prefix` if the id carries it.
+    Uuid { version: Option<usize> },
+    /// Ids the whole pattern matches; anchor it to reject partial matches.
+    Pattern(regex::Regex),
+}
+
+/// Differences between two CSV exports, keyed by user id. All lists are
+/// sorted by id.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Ids only in the second export.
+    pub added: Vec<String>,
+    /// Ids only in the first export.
+    pub removed: Vec<String>,
+    /// Ids in both, with the fields whose values differ.
+    pub changed: Vec<(String, Vec<&'static str>)>,
+}
+
+/// How `restore_from_csv` treats users already in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Refuse to restore unless the store is empty.
+    FailIfNotEmpty,
+    /// Drop everything in the store, then restore the file.
+    Overwrite,
+    /// Keep stored users whose id is in the file; restore the rest.
+    SkipExisting,
+    /// For ids on both sides keep the more recently updated record.
+    Merge,
+}
+
+/// What `store_restored` did with a user, carrying the stored record when
+/// it changed anything.
+#[derive(Debug, Clone)]
+enum MergeOutcome {
+    Created(User),
+    Replaced(User),
+    Skipped,
+}
+
+impl MergeOutcome {
+    fn into_event(self) -> Option<UserEvent> {
+        match self {
+            MergeOutcome::Created(user) => Some(UserEvent::Created(user)),
+            MergeOutcome::Replaced(user) => Some(UserEvent::Updated(user)),
+            MergeOutcome::Skipped => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MergeImportSummary {
+    pub created: usize,
+    pub updated: usize,
+    /// Records no newer than the stored user with their id, or than another
+    /// line of the same batch with their id.
+    pub skipped: usize,
+    /// Lines that did not parse or failed validation or the email check.
+    pub failed: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// On-disk representation for `save` and `load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    /// A single JSON array of users.
+    Json,
+    /// One JSON user per line.
+    Ndjson,
+}
+
+impl Format {
+    fn encode(
+        self,
+        users: &[User],
+        csv_options: &CsvOptions,
+        json_options: JsonOptions,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Format::Csv => {
+                let mut wtr = csv_options.writer_builder().from_writer(vec![]);
+                for user in users {
+                    wtr.serialize(UserCsvRecord::from(user))?;
+                }
+                Ok(wtr.into_inner().map_err(|e| e.into_error())?)
+            }
+            Format::Json if !json_options.age_as_string => Ok(serde_json::to_vec(users)?),
+            Format::Json => {
+                let values = users
+                    .iter()
+                    .map(|user| json_options.to_value(user))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(serde_json::to_vec(&values)?)
+            }
+            Format::Ndjson => {
+                let mut out = Vec::new();
+                for user in users {
+                    serde_json::to_writer(&mut out, &json_options.to_value(user)?)?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    fn decode_rows(
+        self,
+        contents: Vec<u8>,
+        csv_options: &CsvOptions,
+    ) -> Result<Vec<LoadedRow>, Box<dyn std::error::Error + Send + Sync>> {
+        match self {
+            Format::Csv => {
+                let cursor = std::io::Cursor::new(contents);
+                let mut rdr = csv_options.reader_builder().from_reader(cursor);
+                let mut rows = Vec::new();
+                for result in rdr.deserialize() {
+                    let row: LoadedRow =
+                        result.map_err(|e| format!("CSV deserialize error: {}", e))?;
+                    rows.push(row);
+                }
+                Ok(rows)
+            }
+            Format::Json => Ok(serde_json::from_slice(&contents)
+                .map_err(|e| format!("JSON deserialize error: {}", e))?),
+            Format::Ndjson => contents
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| {
+                    serde_json::from_slice(line)
+                        .map_err(|e| format!("NDJSON deserialize error: {}", e).into())
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamSaveReport {
+    pub rows: usize,
+    /// Size of the file on disk, after compression if enabled.
+    pub bytes_written: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ThrottledSummary {
+    pub created: usize,
+    pub failed: usize,
+    pub duration: Duration,
+    /// Requests started per second over the whole run.
+    pub achieved_per_sec: f64,
+}
+
+/// What `create_user_with` does when the email is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CreateConflictPolicy {
+    /// Fail with `UserAlreadyExists`, like `create_user`.
+    #[default]
+    Error,
+    /// Leave the existing user untouched and return it.
+    ReturnExisting,
+    /// Update the existing user's name and age from the request, as
+    /// `upsert_user` does.
+    Update,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    Created,
+    Updated,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BulkUpsertSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub failed: usize,
+}
+
+/// How bulk methods run the inserts of each batch. All strategies insert
+/// the same users and return results in input order; they only differ in
+/// how the work is scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BulkStrategy {
+    /// One tokio task per request. Maximum overlap of the simulated
+    /// validation delay, but a batch spawns as many tasks as it has items.
+    #[default]
+    SpawnPerTask,
+    /// One task per request, but at most this many run at once. Caps memory
+    /// and scheduler pressure at the cost of less overlap.
+    Bounded(usize),
+    /// Inserts synchronously on the rayon pool from a blocking task, after
+    /// waiting out the simulated validation delay once per batch. Keeps the
+    /// tokio workers free, but it cannot overlap real I/O. `Created` events
+    /// are emitted after the whole batch is inserted.
+    RayonBlocking,
+}
+
+/// Whether validation stops at the first violated rule or reports all of
+/// them, joined with `; `, in one `ValidationError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    #[default]
+    FailFast,
+    CollectAll,
+}
+
+/// How much progress reporting the service does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Verbosity {
+    /// Only summary events (batch, bulk and save/load lines); per-item
+    /// events such as `CreatingUser` are dropped before reaching the
+    /// reporter. Use for benchmarks and large runs.
+    Quiet,
+    #[default]
+    Normal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Degraded(Vec<String>),
+}
+
+pub struct UserService {
+    tables: arc_swap::ArcSwap<Tables>,
+    shard_amount: Option<usize>,
+    hasher: MapHasher,
+    stats: Arc<DashMap<(), ServiceStats>>,
+    clock: Arc<dyn Clock>,
+    max_bulk_size: usize,
+    redact_pii: bool,
+    email_normalizer: EmailNormalizer,
+    op_log: OpLog,
+    reporter: Arc<dyn Reporter>,
+    email_history_limit: usize,
+    sequence: AtomicU64,
+    parallel_history: Mutex<VecDeque<ParallelOpRecord>>,
+    idempotency_keys: DashMap<String, (String, chrono::DateTime<chrono::Utc>)>,
+    idempotency_ttl: Duration,
+    count_tx: watch::Sender<usize>,
+    upsert_locks: Vec<tokio::sync::Mutex<()>>,
+    bulk_strategy: BulkStrategy,
+    preserve_email_case: bool,
+    user_created: tokio::sync::Notify,
+    verbosity: Verbosity,
+    bulk_duplicate_policy: DuplicateEmailPolicy,
+    sinks: Vec<Arc<dyn EventSink>>,
+    fault_injector: Option<FaultInjector>,
+    bulk_input_order: bool,
+    email_limits: EmailLimits,
+    on_invalidate: Option<InvalidateHook>,
+    id_format: IdFormat,
+    user_counters: DashMap<String, DashMap<String, AtomicU64>>,
+    validation_mode: ValidationMode,
+    loads_in_flight: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    snapshot_retention: usize,
+    email_required: bool,
+    bulk_transactional: bool,
+    on_operation: Option<OperationHook>,
+    save_retry: RetryPolicy,
+    min_age: u8,
+    country_min_ages: HashMap<String, u8>,
+    events: broadcast::Sender<UserEvent>,
+    bulk_permits: Arc<Semaphore>,
+    generation: AtomicU64,
+    search_cache: Option<SearchCache>,
+    stats_history: Mutex<VecDeque<StatsSample>>,
+    id_prefix: String,
+}
+
+pub struct UserServiceBuilder {
+    clock: Arc<dyn Clock>,
+    max_bulk_size: usize,
+    redact_pii: bool,
+    email_normalizer: EmailNormalizer,
+    recent_ops_capacity: usize,
+    reporter: Arc<dyn Reporter>,
+    email_history_limit: usize,
+    shard_amount: Option<usize>,
+    idempotency_ttl: Duration,
+    bulk_strategy: BulkStrategy,
+    name_index: bool,
+    preserve_email_case: bool,
+    verbosity: Verbosity,
+    bulk_duplicate_policy: DuplicateEmailPolicy,
+    sinks: Vec<Arc<dyn EventSink>>,
+    fault_injector: Option<FaultInjector>,
+    bulk_input_order: bool,
+    email_limits: EmailLimits,
+    on_invalidate: Option<InvalidateHook>,
+    id_format: IdFormat,
+    validation_mode: ValidationMode,
+    snapshot_retention: usize,
+    email_required: bool,
+    bulk_transactional: bool,
+    hasher: MapHasher,
+    on_operation: Option<OperationHook>,
+    save_retry: RetryPolicy,
+    min_age: u8,
+    country_min_ages: HashMap<String, u8>,
+    max_concurrent_bulk: usize,
+    search_cache: Option<(usize, Duration)>,
+    id_prefix: String,
+}
+
+impl UserServiceBuilder {
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn max_bulk_size(mut self, max_bulk_size: usize) -> Self {
+        self.max_bulk_size = max_bulk_size;
+        self
+    }
+
+    /// Replace emails in tracing span fields with a placeholder.
+    pub fn redact_pii(mut self, redact_pii: bool) -> Self {
+        self.redact_pii = redact_pii;
+        self
+    }
+
+    pub fn email_normalizer(mut self, email_normalizer: EmailNormalizer) -> Self {
+        self.email_normalizer = email_normalizer;
+        self
+    }
+
+    /// How many operations `recent_operations` can return; 0 disables the log.
+    pub fn recent_ops_capacity(mut self, recent_ops_capacity: usize) -> Self {
+        self.recent_ops_capacity = recent_ops_capacity;
+        self
+    }
+
+    pub fn reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    /// How many previous emails to keep per user; the oldest are dropped first.
+    pub fn email_history_limit(mut self, email_history_limit: usize) -> Self {
+        self.email_history_limit = email_history_limit;
+        self
+    }
+
+    /// Shard count for the user map and indexes. `dashmap` requires a power
+    /// of two greater than one.
+    pub fn shard_amount(mut self, shard_amount: usize) -> Result<Self, DatabaseError> {
+        if shard_amount < 2 || !shard_amount.is_power_of_two() {
+            return Err(DatabaseError::ValidationError(format!(
+                "Shard amount must be a power of two greater than 1, got {}",
+                shard_amount
+            )));
+        }
+        self.shard_amount = Some(shard_amount);
+        Ok(self)
+    }
+
+    /// How long an idempotency key keeps deduplicating creates.
+    pub fn idempotency_ttl(mut self, idempotency_ttl: Duration) -> Self {
+        self.idempotency_ttl = idempotency_ttl;
+        self
+    }
+
+    pub fn bulk_strategy(mut self, bulk_strategy: BulkStrategy) -> Self {
+        self.bulk_strategy = bulk_strategy;
+        self
+    }
+
+    /// Maintain a name -> ids index for `find_by_name_exact`. Off by default
+    /// since it keeps a second copy of every name.
+    pub fn name_index(mut self, name_index: bool) -> Self {
+        self.name_index = name_index;
+        self
+    }
+
+    /// Store emails as given (trimmed) instead of normalized. The email index
+    /// and uniqueness checks still use the normalized form.
+    pub fn preserve_email_case(mut self, preserve_email_case: bool) -> Self {
+        self.preserve_email_case = preserve_email_case;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// What `bulk_create_users` does when one call repeats an email. Skipped
+    /// requests fail with `DuplicateInBatch`; `Reject` fails the whole call.
+    pub fn bulk_duplicate_policy(mut self, policy: DuplicateEmailPolicy) -> Self {
+        self.bulk_duplicate_policy = policy;
+        self
+    }
+
+    /// Adds a sink notified of every create, update and delete, including
+    /// bulk creates, restores and merge imports. Sinks run in the order they
+    /// were added. `migrate_all`, `replace_dataset` and the clear done by
+    /// `RestorePolicy::Overwrite` replace data wholesale and emit nothing.
+    pub fn event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn fault_injector(mut self, fault_injector: FaultInjector) -> Self {
+        self.fault_injector = Some(fault_injector);
+        self
+    }
+
+    /// Stamp users created by one `bulk_create_users` call with the call's
+    /// start time and consecutive sequence numbers in input order, so
+    /// creation order matches submission order. Off by default, leaving the
+    /// wall-clock time each insert happened to run at.
+    pub fn bulk_input_order(mut self, bulk_input_order: bool) -> Self {
+        self.bulk_input_order = bulk_input_order;
+        self
+    }
+
+    pub fn email_limits(mut self, email_limits: EmailLimits) -> Self {
+        self.email_limits = email_limits;
+        self
+    }
+
+    /// Called with a user's id whenever a stored user changes or is removed,
+    /// after the change is visible. Runs on the mutating thread, which may be
+    /// a rayon worker, so it should be quick and must not call back into the
+    /// service.
+    pub fn on_invalidate(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_invalidate = Some(Box::new(hook));
+        self
+    }
+
+    pub fn id_format(mut self, id_format: IdFormat) -> Self {
+        self.id_format = id_format;
+        self
+    }
+
+    pub fn validation_mode(mut self, validation_mode: ValidationMode) -> Self {
+        self.validation_mode = validation_mode;
+        self
+    }
+
+    /// How many files `save_snapshot` keeps per directory; at least one.
+    pub fn snapshot_retention(mut self, snapshot_retention: usize) -> Self {
+        self.snapshot_retention = snapshot_retention.max(1);
+        self
+    }
+
+    /// Whether users must have an email. When off, users may be created
+    /// without one; uniqueness and the email index only cover present emails.
+    pub fn email_required(mut self, email_required: bool) -> Self {
+        self.email_required = email_required;
+        self
+    }
+
+    /// All-or-nothing `bulk_create_users`: every request is validated, and
+    /// checked against stored and in-batch emails, before anything is
+    /// inserted. Any failure rejects the whole call with `BatchRejected`;
+    /// users already inserted when a later insert fails are deleted again.
+    pub fn bulk_transactional(mut self, bulk_transactional: bool) -> Self {
+        self.bulk_transactional = bulk_transactional;
+        self
+    }
+
+    /// Hash function of the id-keyed user table; `MapHasher::SipHash` unless
+    /// set. The email and name indexes always use SipHash.
+    pub fn hasher(mut self, hasher: MapHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Called after every create, get, update, replace and delete with its
+    /// kind, outcome and duration, for bridging to a metrics system. Like
+    /// `on_invalidate` it may run on a rayon worker, so keep it quick. When
+    /// unset, operations are not timed at all.
+    pub fn on_operation(mut self, hook: impl Fn(OperationMetric) + Send + Sync + 'static) -> Self {
+        self.on_operation = Some(Box::new(hook));
+        self
+    }
+
+    /// Retries of the file writes of the save methods. Each attempt writes a
+    /// fresh temporary file, which replaces the target only once complete.
+    pub fn save_retry(mut self, save_retry: RetryPolicy) -> Self {
+        self.save_retry = save_retry;
+        self
+    }
+
+    /// Youngest accepted age where no country rule applies; 13 unless set.
+    pub fn min_age(mut self, min_age: u8) -> Self {
+        self.min_age = min_age;
+        self
+    }
+
+    /// Minimum ages by country code, for requests that carry a `country`.
+    /// Codes are matched ignoring case.
+    pub fn country_min_ages(mut self, country_min_ages: HashMap<String, u8>) -> Self {
+        self.country_min_ages = country_min_ages
+            .into_iter()
+            .map(|(country, min_age)| (country.trim().to_uppercase(), min_age))
+            .collect();
+        self
+    }
+
+    /// How many bulk creates, upserts, loads, restores and merge imports may
+    /// run at once across all callers; further calls wait for a running one to finish. At least one.
+    pub fn max_concurrent_bulk(mut self, max_concurrent_bulk: usize) -> Self {
+        self.max_concurrent_bulk = max_concurrent_bulk.max(1);
+        self
+    }
+
+    /// Caches the results of up to `capacity` distinct searches for `ttl`,
+    /// evicting the least recently used. Any insert, update or delete makes
+    /// every cached result stale. Off unless set.
+    pub fn search_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.search_cache = Some((capacity.max(1), ttl));
+        self
+    }
+
+    /// Prepends `prefix` to every id the service generates, e.g. `usr_`
+    /// for `usr_<uuid>`. Ids restored from a file keep whatever they had.
+    pub fn id_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.id_prefix = prefix.into();
+        self
+    }
+
+    pub fn build(self) -> UserService {
+        UserService {
+            tables: arc_swap::ArcSwap::from_pointee(Tables::new(
+                self.shard_amount,
+                self.hasher,
+                self.name_index,
+            )),
+            shard_amount: self.shard_amount,
+            hasher: self.hasher,
+            stats: Arc::new(DashMap::new()),
+            clock: self.clock,
+            max_bulk_size: self.max_bulk_size,
+            redact_pii: self.redact_pii,
+            email_normalizer: self.email_normalizer,
+            op_log: OpLog::new(self.recent_ops_capacity),
+            reporter: self.reporter,
+            email_history_limit: self.email_history_limit,
+            sequence: AtomicU64::new(0),
+            parallel_history: Mutex::new(VecDeque::new()),
+            idempotency_keys: DashMap::new(),
+            idempotency_ttl: self.idempotency_ttl,
+            count_tx: watch::Sender::new(0),
+            upsert_locks: (0..UPSERT_LOCK_STRIPES)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+            bulk_strategy: self.bulk_strategy,
+            preserve_email_case: self.preserve_email_case,
+            user_created: tokio::sync::Notify::new(),
+            verbosity: self.verbosity,
+            bulk_duplicate_policy: self.bulk_duplicate_policy,
+            sinks: self.sinks,
+            fault_injector: self.fault_injector,
+            bulk_input_order: self.bulk_input_order,
+            email_limits: self.email_limits,
+            on_invalidate: self.on_invalidate,
+            id_format: self.id_format,
+            user_counters: DashMap::new(),
+            validation_mode: self.validation_mode,
+            loads_in_flight: DashMap::new(),
+            snapshot_retention: self.snapshot_retention,
+            email_required: self.email_required,
+            bulk_transactional: self.bulk_transactional,
+            on_operation: self.on_operation,
+            save_retry: self.save_retry,
+            min_age: self.min_age,
+            country_min_ages: self.country_min_ages,
+            events: broadcast::Sender::new(EVENT_BROADCAST_CAPACITY),
+            bulk_permits: Arc::new(Semaphore::new(self.max_concurrent_bulk)),
+            generation: AtomicU64::new(0),
+            search_cache: self
+                .search_cache
+                .map(|(capacity, ttl)| SearchCache::new(capacity, ttl)),
+            stats_history: Mutex::new(VecDeque::new()),
+            id_prefix: self.id_prefix,
+        }
+    }
+}
+
+impl Default for UserServiceBuilder {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            max_bulk_size: DEFAULT_MAX_BULK_SIZE,
+            redact_pii: false,
+            email_normalizer: EmailNormalizer::default(),
+            recent_ops_capacity: DEFAULT_RECENT_OPS_CAPACITY,
+            reporter: Arc::new(ConsoleReporter),
+            email_history_limit: DEFAULT_EMAIL_HISTORY_LIMIT,
+            shard_amount: None,
+            idempotency_ttl: DEFAULT_IDEMPOTENCY_TTL,
+            bulk_strategy: BulkStrategy::default(),
+            name_index: false,
+            preserve_email_case: false,
+            verbosity: Verbosity::default(),
+            bulk_duplicate_policy: DuplicateEmailPolicy::KeepFirst,
+            sinks: Vec::new(),
+            fault_injector: None,
+            bulk_input_order: false,
+            email_limits: EmailLimits::default(),
+            on_invalidate: None,
+            id_format: IdFormat::default(),
+            validation_mode: ValidationMode::default(),
+            snapshot_retention: DEFAULT_SNAPSHOT_RETENTION,
+            email_required: true,
+            bulk_transactional: false,
+            hasher: MapHasher::default(),
+            on_operation: None,
+            save_retry: RetryPolicy::default(),
+            min_age: DEFAULT_MIN_AGE,
+            country_min_ages: HashMap::new(),
+            max_concurrent_bulk: DEFAULT_MAX_CONCURRENT_BULK,
+            search_cache: None,
+            id_prefix: String::new(),
+        }
+    }
+}
+
+impl UserService {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> UserServiceBuilder {
+        UserServiceBuilder::default()
+    }
+
+    /// Cancellation-safe: the only await before the insert is the validation
+    /// delay, and the map and index writes happen in one synchronous step, so
+    /// dropping the future earlier leaves nothing behind. Dropping it while
+    /// sinks are notified keeps the user, but sinks may miss its `Created`.
+    #[tracing::instrument(skip_all, fields(id, email))]
+    pub async fn create_user(&self, req: CreateUserRequest) -> Result<User, DatabaseError> {
+        let (user, replayed) = self.create_user_unannounced(req).await?;
+        if !replayed {
+            self.emit(UserEvent::Created(user.clone())).await;
+        }
+        Ok(user)
+    }
+
+    /// `create_user` without the `Created` event, also telling whether the
+    /// user came from an idempotency-key replay. For bulk paths that emit
+    /// once the users are final.
+    async fn create_user_unannounced(
+        &self,
+        req: CreateUserRequest,
+    ) -> Result<(User, bool), DatabaseError> {
+        self.observed(OpKind::Create, async {
+            sleep(VALIDATION_DELAY).await;
+            self.create_user_after_delay(req)
+        })
+        .await
+    }
+
+    async fn observed<T>(
+        &self,
+        kind: OpKind,
+        op: impl std::future::Future<Output = Result<T, DatabaseError>>,
+    ) -> Result<T, DatabaseError> {
+        let Some(hook) = &self.on_operation else {
+            return op.await;
+        };
+        let start = Instant::now();
+        let result = op.await;
+        hook(OperationMetric {
+            kind,
+            success: result.is_ok(),
+            duration: start.elapsed(),
+        });
+        result
+    }
+
+    fn observed_blocking<T>(
+        &self,
+        kind: OpKind,
+        op: impl FnOnce() -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        let Some(hook) = &self.on_operation else {
+            return op();
+        };
+        let start = Instant::now();
+        let result = op();
+        hook(OperationMetric {
+            kind,
+            success: result.is_ok(),
+            duration: start.elapsed(),
+        });
+        result
+    }
+
+    /// Waits for a slot under `max_concurrent_bulk`; the slot is released
+    /// when the permit is dropped.
+    async fn bulk_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        Arc::clone(&self.bulk_permits)
+            .acquire_owned()
+            .await
+            .expect("bulk semaphore is never closed")
+    }
+
+    fn invalidate(&self, id: &str) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        if let Some(hook) = &self.on_invalidate {
+            hook(id);
+        }
+    }
+
+    fn inject_fault(&self, id: Option<&str>) -> bool {
+        self.fault_injector
+            .as_ref()
+            .is_some_and(|injector| injector.should_fail(id))
+    }
+
+    /// Whether anything would receive an emitted event, so bulk paths can
+    /// skip collecting events nobody reads.
+    fn has_event_consumers(&self) -> bool {
+        self.events.receiver_count() > 0 || !self.sinks.is_empty()
+    }
+
+    async fn emit(&self, event: UserEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event.clone());
+        }
+        for sink in &self.sinks {
+            if let Err(e) = sink.handle(event.clone()).await {
+                eprintln!("❌ Event sink failed: {}", e);
+            }
+        }
+    }
+
+    /// The synchronous core of `create_user`, without the simulated
+    /// validation latency or the `Created` event, which the caller emits for
+    /// non-replayed users once back in async code. Safe to call from rayon
+    /// workers.
+    fn create_user_blocking(&self, req: CreateUserRequest) -> Result<(User, bool), DatabaseError> {
+        self.observed_blocking(OpKind::Create, || self.create_user_after_delay(req))
+    }
+
+    /// Everything `create_user` does between the validation delay and the
+    /// event: fault injection, validation and the insert.
+    fn create_user_after_delay(
+        &self,
+        req: CreateUserRequest,
+    ) -> Result<(User, bool), DatabaseError> {
+        if self.inject_fault(None) {
+            self.apply_stat(|stats| stats.create_failed += 1);
+            self.record_op(OpKind::Create, None, false);
+            return Err(DatabaseError::Injected);
+        }
+        self.create_user_replayable(req)
+    }
+
+    /// `create_user_blocking`, also telling whether the user came from an
+    /// idempotency-key replay rather than a fresh insert.
+    fn create_user_replayable(
+        &self,
+        req: CreateUserRequest,
+    ) -> Result<(User, bool), DatabaseError> {
+        if let Err(e) = self.validate_fields(
+            &req.name,
+            req.email.as_deref(),
+            req.age,
+            req.country.as_deref(),
+        ) {
+            self.apply_stat(|stats| {
+                stats.validation_failed += 1;
+                stats.create_failed += 1;
+            });
+            self.record_op(OpKind::Create, None, false);
+            return Err(e);
+        }
+        let now = self.clock.now();
+        let user = User {
+            id: self.new_id(),
+            name: req.name,
+            email: req.email.as_deref().map(|email| self.stored_email(email)),
+            age: req.age,
+            created_at: now,
+            updated_at: now,
+            email_history: Vec::new(),
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            name_updated_at: None,
+            email_updated_at: None,
+            age_updated_at: None,
+        };
+        tracing::Span::current().record("id", user.id.as_str());
+        self.record_email(user.email.as_deref());
+
+        let result = match req.idempotency_key {
+            Some(key) => self.insert_idempotent(user, key),
+            None => self.insert_new_user(user).map(|user| (user, false)),
+        };
+        match result {
+            Ok((user, false)) => {
+                self.apply_stat(|stats| stats.create_count += 1);
+                self.record_op(OpKind::Create, Some(&user.id), true);
+                Ok((user, false))
+            }
+            Ok((user, true)) => {
+                self.apply_stat(|stats| stats.read_count += 1);
+                self.record_op(OpKind::Read, Some(&user.id), true);
+                Ok((user, true))
+            }
+            Err(e) => {
+                self.apply_stat(|stats| stats.create_failed += 1);
+                self.record_op(OpKind::Create, None, false);
+                Err(e)
+            }
+        }
+    }
+
+    /// Creates `user` unless `key` was already used within the idempotency
+    /// TTL, in which case the user created by that earlier call is returned
+    /// instead. The key's entry stays locked for the whole insert, so
+    /// concurrent retries of the same key cannot both create. The flag is
+    /// `true` for a replay.
+    fn insert_idempotent(&self, user: User, key: String) -> Result<(User, bool), DatabaseError> {
+        let tables = self.tables.load_full();
+        let now = self.clock.now();
+        let ttl = chrono::Duration::from_std(self.idempotency_ttl).unwrap_or(chrono::Duration::MAX);
+        match self.idempotency_keys.entry(key) {
+            Entry::Occupied(mut slot) => {
+                let (existing_id, recorded_at) = slot.get().clone();
+                if now - recorded_at < ttl
+                    && let Some(existing) = tables.db.get(&existing_id)
+                {
+                    return Ok((existing.value().clone(), true));
+                }
+                let user = self.insert_new_user(user)?;
+                slot.insert((user.id.clone(), now));
+                Ok((user, false))
+            }
+            Entry::Vacant(slot) => {
+                let user = self.insert_new_user(user)?;
+                slot.insert((user.id.clone(), now));
+                Ok((user, false))
+            }
+        }
+    }
+
+    /// Inserts a freshly built user, enforcing id and email uniqueness. Never
+    /// awaits, so a caller cannot be cancelled between the index and map
+    /// writes.
+    fn insert_new_user(&self, user: User) -> Result<User, DatabaseError> {
+        let tables = self.tables.load_full();
+        // The id is claimed through its map entry, so two inserts racing on
+        // one id cannot both get past this check. Holding it while taking the
+        // index entry follows the update paths' order: `db`, then the index.
+        let Entry::Vacant(slot) = tables.db.entry(user.id.clone()) else {
+            return Err(DatabaseError::UserAlreadyExists);
+        };
+        if let Some(email) = &user.email {
+            match tables.email_index.entry(self.email_key(email)) {
+                Entry::Occupied(_) => return Err(DatabaseError::UserAlreadyExists),
+                Entry::Vacant(index) => {
+                    index.insert(user.id.clone());
+                }
+            }
+        }
+        slot.insert(user.clone());
+        self.index_name(&user.name, &user.id);
+        self.publish_count();
+        self.user_created.notify_waiters();
+        Ok(user)
+    }
+
+    /// The form an email is stored in on the user record.
+    fn stored_email(&self, email: &str) -> String {
+        if self.preserve_email_case {
+            email.trim().to_string()
+        } else {
+            self.email_normalizer.normalize(email)
+        }
+    }
+
+    /// The form an email is stored in on the email index.
+    fn email_key(&self, email: &str) -> String {
+        self.email_normalizer.normalize(email)
+    }
+
+    fn name_key(name: &str) -> String {
+        name.trim().to_lowercase()
+    }
+
+    fn index_name(&self, name: &str, id: &str) {
+        let tables = self.tables.load_full();
+        if let Some(index) = &tables.name_index {
+            index
+                .entry(Self::name_key(name))
+                .or_default()
+                .push(id.to_string());
+        }
+    }
+
+    fn unindex_name(&self, name: &str, id: &str) {
+        let tables = self.tables.load_full();
+        if let Some(index) = &tables.name_index
+            && let Entry::Occupied(mut slot) = index.entry(Self::name_key(name))
+        {
+            slot.get_mut().retain(|existing| existing != id);
+            if slot.get().is_empty() {
+                slot.remove();
+            }
+        }
+    }
+
+    /// Users whose name matches `name`, ignoring case and surrounding
+    /// whitespace. Uses the name index when enabled and falls back to a
+    /// parallel scan otherwise.
+    pub fn find_by_name_exact(&self, name: &str) -> Vec<User> {
+        let tables = self.tables.load_full();
+        let key = Self::name_key(name);
+        match &tables.name_index {
+            Some(index) => {
+                let ids = index.get(&key).map(|ids| ids.clone()).unwrap_or_default();
+                ids.iter()
+                    .filter_map(|id| tables.db.get(id).map(|user| user.value().clone()))
+                    .collect()
+            }
+            None => on_rayon(|| {
+                tables
+                    .db
+                    .par_iter()
+                    .filter(|entry| Self::name_key(&entry.name) == key)
+                    .map(|entry| entry.value().clone())
+                    .collect()
+            }),
+        }
+    }
+
+    /// Drops idempotency keys older than the TTL. Expired keys are also
+    /// ignored on lookup, so this only reclaims memory.
+    pub fn purge_expired_idempotency_keys(&self) {
+        let now = self.clock.now();
+        let ttl = chrono::Duration::from_std(self.idempotency_ttl).unwrap_or(chrono::Duration::MAX);
+        self.idempotency_keys
+            .retain(|_, (_, recorded_at)| now - *recorded_at < ttl);
+    }
+
+    #[tracing::instrument(skip(self), fields(email))]
+    pub async fn get_user(&self, id: &str) -> Result<User, DatabaseError> {
+        self.observed(OpKind::Read, self.read_user(id)).await
+    }
+
+    /// `get_user` without the `on_operation` report, for operations that
+    /// read back the user they changed and report themselves.
+    async fn read_user(&self, id: &str) -> Result<User, DatabaseError> {
+        let tables = self.tables.load_full();
+        if self.inject_fault(Some(id)) {
+            self.record_op(OpKind::Read, Some(id), false);
+            return Err(DatabaseError::Injected);
+        }
+        let found = tables.db.get(id).map(|user| user.value().clone());
+        match found {
+            Some(user) => Ok(self.record_hit(id, user).await),
+            None => {
+                self.record_op(OpKind::Read, Some(id), false);
+                Err(DatabaseError::UserNotFound)
+            }
+        }
+    }
+
+    /// Counts a successful read of `user` and hands it back.
+    async fn record_hit(&self, id: &str, user: User) -> User {
+        self.record_email(user.email.as_deref());
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        self.record_op(OpKind::Read, Some(id), true);
+        user
+    }
+
+    /// Like `get_user`, but on a miss waits for the user to be created, giving
+    /// up with `Timeout` once `timeout` has elapsed.
+    pub async fn get_user_await(&self, id: &str, timeout: Duration) -> Result<User, DatabaseError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Register for the wakeup before checking, so a create landing in
+            // between is not missed.
+            let created = self.user_created.notified();
+            tokio::pin!(created);
+            created.as_mut().enable();
+            // Reloaded on every pass: the dataset may have been swapped
+            // while this call was waiting. The user is read once, so a delete
+            // racing this pass means waiting on rather than a miss.
+            let tables = self.tables.load_full();
+            let found = tables.db.get(id).map(|user| user.value().clone());
+            if let Some(user) = found {
+                return self
+                    .observed(OpKind::Read, async { Ok(self.record_hit(id, user).await) })
+                    .await;
+            }
+            if tokio::time::timeout_at(deadline, created).await.is_err() {
+                self.record_op(OpKind::Read, Some(id), false);
+                return Err(DatabaseError::Timeout);
+            }
+        }
+    }
+
+    /// Like `get_user`, but on a miss fetches the user with `loader`, stores it
+    /// under `id` and returns it. Concurrent misses for one id share a single
+    /// loader call; the others wait for it and then read the stored user. If
+    /// the loader fails, the next waiter tries its own.
+    pub async fn get_user_or_load<F, Fut>(&self, id: &str, loader: F) -> Result<User, DatabaseError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<User, DatabaseError>>,
+    {
+        let tables = self.tables.load_full();
+        if tables.db.contains_key(id) {
+            return self.get_user(id).await;
+        }
+        let flight = InFlightLoad {
+            loads: &self.loads_in_flight,
+            id,
+            lock: Arc::clone(
+                self.loads_in_flight
+                    .entry(id.to_string())
+                    .or_default()
+                    .value(),
+            ),
+        };
+        let guard = flight.lock.lock().await;
+        // Another load may have stored the user, or the dataset been swapped,
+        // while this call waited for the lock.
+        if self.tables.load().db.contains_key(id) {
+            return self.get_user(id).await;
+        }
+
+        let result = async {
+            let mut user = loader().await?;
+            self.validate_id(id)?;
+            self.validate_fields(&user.name, user.email.as_deref(), user.age, None)?;
+            user.id = id.to_string();
+            user.email = user.email.map(|email| self.stored_email(&email));
+            user.sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+            self.insert_new_user(user)
+        }
+        .await;
+        drop(guard);
+        drop(flight);
+        let user = result?;
+        self.increment_stat(|stats| stats.create_count += 1).await;
+        self.record_op(OpKind::Create, Some(id), true);
+        self.emit(UserEvent::Created(user.clone())).await;
+        Ok(user)
+    }
+
+    /// The user as a JSON tree, for handlers that add computed fields before
+    /// responding.
+    pub async fn get_user_json(&self, id: &str) -> Result<serde_json::Value, DatabaseError> {
+        let user = self.get_user(id).await?;
+        serde_json::to_value(user).map_err(|e| DatabaseError::ValidationError(e.to_string()))
+    }
+
+    /// The user as a JSON object holding only `fields`, keyed by their
+    /// `Field` names, to keep responses small for clients that need a few.
+    pub async fn get_user_projected(
+        &self,
+        id: &str,
+        fields: &[Field],
+    ) -> Result<serde_json::Value, DatabaseError> {
+        let user = self.get_user(id).await?;
+        let object = fields
+            .iter()
+            .map(|&field| (field.header().to_string(), field.json_value(&user)))
+            .collect();
+        Ok(serde_json::Value::Object(object))
+    }
+
+    pub async fn list_users_json(&self) -> Result<serde_json::Value, DatabaseError> {
+        let users = self.list_users().await?;
+        serde_json::to_value(users).map_err(|e| DatabaseError::ValidationError(e.to_string()))
+    }
+
+    /// Looks a user up through the email index, so the match follows the
+    /// configured normalizer rather than the stored spelling.
+    pub async fn get_user_by_email(&self, email: &str) -> Result<User, DatabaseError> {
+        let tables = self.tables.load_full();
+        let id = tables
+            .email_index
+            .get(&self.email_key(email))
+            .map(|id| id.value().clone());
+        match id {
+            Some(id) => self.get_user(&id).await,
+            None => {
+                self.record_op(OpKind::Read, None, false);
+                Err(DatabaseError::UserNotFound)
+            }
+        }
+    }
+
+    /// Resolves many emails through the index in parallel. Results are in
+    /// input order, with `UserNotFound` for emails nobody holds.
+    pub async fn get_many_by_email(&self, emails: &[String]) -> Vec<Result<User, DatabaseError>> {
+        let tables = self.tables.load_full();
+        let results: Vec<_> = on_rayon(|| {
+            emails
+                .par_iter()
+                .map(|email| {
+                    // Copy the id out so the index guard is gone before `db` is read.
+                    let id = tables
+                        .email_index
+                        .get(&self.email_key(email))
+                        .map(|id| id.value().clone())
+                        .ok_or(DatabaseError::UserNotFound)?;
+                    tables
+                        .db
+                        .get(&id)
+                        .map(|user| user.value().clone())
+                        .ok_or(DatabaseError::UserNotFound)
+                })
+                .collect()
+        });
+        let hits = results.iter().filter(|r| r.is_ok()).count() as u64;
+        // One read per hit on top of the call itself, which `increment_stat`
+        // already counts.
+        self.increment_stat(|stats| {
+            stats.total_operations += hits;
+            stats.read_count += hits;
+        })
+        .await;
+        results
+    }
+
+    /// Like `get_user`, but a miss is an expected outcome: it returns `None`
+    /// without recording a failed read.
+    pub async fn get_user_opt(&self, id: &str) -> Option<User> {
+        let tables = self.tables.load_full();
+        let user = tables.db.get(id).map(|user| user.value().clone())?;
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        self.record_op(OpKind::Read, Some(id), true);
+        Some(user)
+    }
+
+    pub async fn get_user_or(&self, id: &str, default: User) -> User {
+        self.get_user_opt(id).await.unwrap_or(default)
+    }
+
+    /// Runs `f` against the stored user without cloning it. The closure executes
+    /// while the map's read guard is held, so it must not call back into the
+    /// service (doing so on the same shard will deadlock).
+    pub async fn with_user<F, T>(&self, id: &str, f: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&User) -> T,
+    {
+        let tables = self.tables.load_full();
+        let result = match tables.db.get(id) {
+            Some(user) => f(user.value()),
+            None => return Err(DatabaseError::UserNotFound),
+        };
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        Ok(result)
+    }
+
+    #[tracing::instrument(skip(self, req), fields(email))]
+    pub async fn update_user(
+        &self,
+        id: &str,
+        req: UpdateUserRequest,
+    ) -> Result<User, DatabaseError> {
+        self.observed(OpKind::Update, async {
+            let changed = self.with_user_mut(id, |user| {
+                let now = self.clock.now();
+                if let Some(email) = req.email {
+                    self.change_email(user, Some(&email), now)?;
+                }
+                self.record_email(user.email.as_deref());
+                if let Some(name) = req.name
+                    && name != user.name
+                {
+                    self.unindex_name(&user.name, id);
+                    self.index_name(&name, id);
+                    user.name = name;
+                    user.name_updated_at = Some(now);
+                }
+                if let Some(age) = req.age
+                    && Some(age) != user.age
+                {
+                    user.age = Some(age);
+                    user.age_updated_at = Some(now);
+                }
+                user.updated_at = now;
+                Ok(())
+            });
+            if let Err(e) = changed.and_then(|result| result) {
+                self.increment_stat(|stats| stats.update_failed += 1).await;
+                self.record_op(OpKind::Update, Some(id), false);
+                return Err(e);
+            }
+            self.invalidate(id);
+            self.increment_stat(|stats| stats.update_count += 1).await;
+            self.record_op(OpKind::Update, Some(id), true);
+            let user = self.read_user(id).await?;
+            self.emit(UserEvent::Updated(user.clone())).await;
+            Ok(user)
+        })
+        .await
+    }
+
+    /// Runs `f` on the stored user while holding its shard's write lock, or
+    /// fails with `UserNotFound`. Map guards must never be held across an
+    /// `.await`: the parked task keeps the shard locked, and any other task
+    /// touching that shard blocks its worker thread, which can stall the
+    /// whole runtime. `f` is synchronous, so the lock is always released
+    /// before the caller can await; mutate through this instead of keeping
+    /// a `get_mut` guard in async code.
+    fn with_user_mut<R>(
+        &self,
+        id: &str,
+        f: impl FnOnce(&mut User) -> R,
+    ) -> Result<R, DatabaseError> {
+        let tables = self.tables.load_full();
+        let mut user = tables.db.get_mut(id).ok_or(DatabaseError::UserNotFound)?;
+        Ok(f(&mut user))
+    }
+
+    /// Overwrites every mutable field with `req`, so an absent age clears the
+    /// stored one. The id and `created_at` are kept; the request is
+    /// validated as a whole before anything changes. `User` has no version
+    /// field, so `updated_at` is the only marker of the change.
+    pub async fn replace_user(
+        &self,
+        id: &str,
+        req: CreateUserRequest,
+    ) -> Result<User, DatabaseError> {
+        self.observed(OpKind::Update, async {
+            if let Err(e) = self.validate_fields(
+                &req.name,
+                req.email.as_deref(),
+                req.age,
+                req.country.as_deref(),
+            ) {
+                self.increment_stat(|stats| stats.update_failed += 1).await;
+                self.record_op(OpKind::Update, Some(id), false);
+                return Err(e);
+            }
+            let changed = self.with_user_mut(id, |user| {
+                let now = self.clock.now();
+                self.change_email(user, req.email.as_deref(), now)?;
+                self.record_email(user.email.as_deref());
+                if req.name != user.name {
+                    self.unindex_name(&user.name, id);
+                    self.index_name(&req.name, id);
+                    user.name = req.name;
+                    user.name_updated_at = Some(now);
+                }
+                if req.age != user.age {
+                    user.age = req.age;
+                    user.age_updated_at = Some(now);
+                }
+                user.updated_at = now;
+                Ok(())
+            });
+            if let Err(e) = changed.and_then(|result| result) {
+                self.increment_stat(|stats| stats.update_failed += 1).await;
+                self.record_op(OpKind::Update, Some(id), false);
+                return Err(e);
+            }
+            self.invalidate(id);
+            self.increment_stat(|stats| stats.update_count += 1).await;
+            self.record_op(OpKind::Update, Some(id), true);
+            let user = self.read_user(id).await?;
+            self.emit(UserEvent::Updated(user.clone())).await;
+            Ok(user)
+        })
+        .await
+    }
+
+    /// Moves `user` to `email`, keeping the index and email history in step.
+    /// Called with the user's map guard held, which is the lock order the
+    /// index expects.
+    fn change_email(
+        &self,
+        user: &mut User,
+        email: Option<&str>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DatabaseError> {
+        let email = email.map(|email| self.stored_email(email));
+        if email == user.email {
+            return Ok(());
+        }
+        self.reindex_email(&user.id, user.email.as_deref(), email.as_deref())?;
+        if let Some(previous) = std::mem::replace(&mut user.email, email) {
+            user.email_history.push((previous, now));
+            let excess = user
+                .email_history
+                .len()
+                .saturating_sub(self.email_history_limit);
+            user.email_history.drain(..excess);
+        }
+        user.email_updated_at = Some(now);
+        Ok(())
+    }
+
+    /// Moves `id`'s email index entry from `old` to `new`. Fails with
+    /// `UserAlreadyExists`, changing nothing, when another user holds `new`.
+    /// Callers hold the user's map guard, which is the lock order the index
+    /// expects.
+    fn reindex_email(
+        &self,
+        id: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        let tables = self.tables.load_full();
+        let old_key = old.map(|email| self.email_key(email));
+        let new_key = new.map(|email| self.email_key(email));
+        // A change in case only keeps the same index entry.
+        if old_key == new_key {
+            return Ok(());
+        }
+        if let Some(key) = new_key {
+            match tables.email_index.entry(key) {
+                Entry::Occupied(_) => return Err(DatabaseError::UserAlreadyExists),
+                Entry::Vacant(slot) => {
+                    slot.insert(id.to_string());
+                }
+            }
+        }
+        if let Some(key) = old_key {
+            tables.email_index.remove(&key);
+        }
+        Ok(())
+    }
+
+    pub async fn create_user_with(
+        &self,
+        req: CreateUserRequest,
+        policy: CreateConflictPolicy,
+    ) -> Result<User, DatabaseError> {
+        let tables = self.tables.load_full();
+        match policy {
+            CreateConflictPolicy::Error => self.create_user(req).await,
+            CreateConflictPolicy::ReturnExisting => {
+                let Some(email) = req.email.clone() else {
+                    return self.create_user(req).await;
+                };
+                if tables.email_index.contains_key(&self.email_key(&email)) {
+                    return self.get_user_by_email(&email).await;
+                }
+                match self.create_user(req).await {
+                    // Lost a race with another create of the same email.
+                    Err(DatabaseError::UserAlreadyExists) => self.get_user_by_email(&email).await,
+                    result => result,
+                }
+            }
+            CreateConflictPolicy::Update => self.upsert_user(req).await.map(|(user, _)| user),
+        }
+    }
+
+    /// Creates the user, or updates the one already holding the request's
+    /// email. Upserts of the same email are serialized by a striped lock, so
+    /// they cannot both decide to create. A request without an email always
+    /// creates.
+    pub async fn upsert_user(
+        &self,
+        req: CreateUserRequest,
+    ) -> Result<(User, UpsertOutcome), DatabaseError> {
+        let tables = self.tables.load_full();
+        let Some(email) = req.email.as_deref().map(|email| self.email_key(email)) else {
+            let user = self.create_user(req).await?;
+            return Ok((user, UpsertOutcome::Created));
+        };
+        let _guard = self.upsert_locks[shard_for(&email, self.upsert_locks.len())]
+            .lock()
+            .await;
+
+        let existing_id = tables.email_index.get(&email).map(|id| id.value().clone());
+        match existing_id {
+            Some(id) => {
+                self.validate_fields(
+                    &req.name,
+                    req.email.as_deref(),
+                    req.age,
+                    req.country.as_deref(),
+                )?;
+                let update = UpdateUserRequest {
+                    name: Some(req.name),
+                    email: None,
+                    age: req.age,
+                };
+                let user = self.update_user(&id, update).await?;
+                Ok((user, UpsertOutcome::Updated))
+            }
+            None => {
+                let user = self.create_user(req).await?;
+                Ok((user, UpsertOutcome::Created))
+            }
+        }
+    }
+
+    pub async fn bulk_upsert_users(
+        self: Arc<Self>,
+        reqs: Vec<CreateUserRequest>,
+    ) -> Result<BulkUpsertSummary, DatabaseError> {
+        let _permit = self.bulk_permit().await;
+        self.check_bulk_size(reqs.len())?;
+        let mut results = stream::iter(reqs)
+            .map(|req| {
+                let svc = Arc::clone(&self);
+                async move { svc.upsert_user(req).await }
+            })
+            .buffer_unordered(BULK_BATCH_SIZE);
+
+        let mut summary = BulkUpsertSummary::default();
+        while let Some(result) = results.next().await {
+            match result {
+                Ok((_, UpsertOutcome::Created)) => summary.created += 1,
+                Ok((_, UpsertOutcome::Updated)) => summary.updated += 1,
+                Err(_) => summary.failed += 1,
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Applies `f` to every user in parallel. Users the migration would leave
+    /// invalid, or whose new email is already taken, keep their old record
+    /// and are reported as `MigrationSkipped`. Returns how many were changed.
+    /// Emits no events; subscribers should treat a migration like a reload.
+    pub fn migrate_all<F>(&self, f: F) -> usize
+    where
+        F: Fn(&mut User) + Sync + Send,
+    {
+        let tables = self.tables.load_full();
+        let now = self.clock.now();
+        let migrated = on_rayon(|| {
+            tables
+                .db
+                .par_iter_mut()
+                .map(|mut entry| {
+                    let id = entry.key().clone();
+                    let mut user = entry.value().clone();
+                    f(&mut user);
+                    user.id = id.clone();
+                    user.email = user.email.map(|email| self.stored_email(&email));
+
+                    let skip = |reason: String| {
+                        self.reporter.report(ProgressEvent::MigrationSkipped {
+                            id: id.clone(),
+                            reason,
+                        });
+                        false
+                    };
+                    if let Err(e) =
+                        self.validate_fields(&user.name, user.email.as_deref(), user.age, None)
+                    {
+                        return skip(e.to_string());
+                    }
+                    if self
+                        .reindex_email(&id, entry.email.as_deref(), user.email.as_deref())
+                        .is_err()
+                    {
+                        return skip("email already in use".to_string());
+                    }
+                    if user.name != entry.name {
+                        self.unindex_name(&entry.name, &id);
+                        self.index_name(&user.name, &id);
+                    }
+                    user.updated_at = now;
+                    *entry.value_mut() = user;
+                    drop(entry);
+                    self.invalidate(&id);
+                    true
+                })
+                .filter(|&migrated| migrated)
+                .count()
+        });
+        // One update per migrated user on top of the parallel operation
+        // itself, which `apply_stat` already counts.
+        self.apply_stat(|stats| {
+            stats.total_operations += migrated as u64;
+            stats.update_count += migrated as u64;
+            stats.parallel_operations += 1;
+        });
+        migrated
+    }
+
+    /// Adds `by` to the user's counter `name`, creating it at zero, and
+    /// returns the new value. Increments of an existing counter only take
+    /// read locks, so concurrent callers do not serialize on the map.
+    pub fn increment_user_counter(
+        &self,
+        id: &str,
+        name: &str,
+        by: u64,
+    ) -> Result<u64, DatabaseError> {
+        let tables = self.tables.load_full();
+        if !tables.db.contains_key(id) {
+            return Err(DatabaseError::UserNotFound);
+        }
+        if let Some(counters) = self.user_counters.get(id)
+            && let Some(counter) = counters.get(name)
+        {
+            return Ok(counter.fetch_add(by, Ordering::Relaxed) + by);
+        }
+        let counters = self.user_counters.entry(id.to_string()).or_default();
+        let counter = counters.entry(name.to_string()).or_default();
+        Ok(counter.fetch_add(by, Ordering::Relaxed) + by)
+    }
+
+    /// The user's counter `name`; 0 if it was never incremented.
+    pub fn get_user_counter(&self, id: &str, name: &str) -> Result<u64, DatabaseError> {
+        let tables = self.tables.load_full();
+        if !tables.db.contains_key(id) {
+            return Err(DatabaseError::UserNotFound);
+        }
+        Ok(self
+            .user_counters
+            .get(id)
+            .and_then(|counters| {
+                counters
+                    .get(name)
+                    .map(|counter| counter.load(Ordering::Relaxed))
+            })
+            .unwrap_or_default())
+    }
+
+    pub async fn email_history(
+        &self,
+        id: &str,
+    ) -> Result<Vec<(String, chrono::DateTime<chrono::Utc>)>, DatabaseError> {
+        self.with_user(id, |user| user.email_history.clone()).await
+    }
+
+    #[tracing::instrument(skip(self), fields(email))]
+    pub async fn delete_user(&self, id: &str) -> Result<User, DatabaseError> {
+        self.observed(OpKind::Delete, async {
+            let tables = self.tables.load_full();
+            match tables.db.remove(id) {
+                Some((_, user)) => {
+                    if let Some(email) = &user.email {
+                        tables.email_index.remove(&self.email_key(email));
+                    }
+                    self.unindex_name(&user.name, id);
+                    self.user_counters.remove(id);
+                    self.publish_count();
+                    self.invalidate(id);
+                    self.record_email(user.email.as_deref());
+                    self.increment_stat(|stats| stats.delete_count += 1).await;
+                    self.record_op(OpKind::Delete, Some(id), true);
+                    self.emit(UserEvent::Deleted(user.clone())).await;
+                    Ok(user)
+                }
+                None => {
+                    self.increment_stat(|stats| stats.delete_failed += 1).await;
+                    self.record_op(OpKind::Delete, Some(id), false);
+                    Err(DatabaseError::UserNotFound)
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>, DatabaseError> {
+        let tables = self.tables.load_full();
+        let users = tables.db.iter().map(|kv| kv.value().clone()).collect();
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        Ok(users)
+    }
+
+    /// All users in creation order, oldest first. Ties on `created_at` are
+    /// broken by the creation sequence, so the order is total.
+    pub async fn list_users_by_creation(&self) -> Result<Vec<User>, DatabaseError> {
+        let mut users = self.list_users().await?;
+        on_rayon(|| users.par_sort_unstable_by_key(|user| (user.created_at, user.sequence)));
+        Ok(users)
+    }
+
+    pub async fn list_sorted(&self, by: SortKey, order: Order) -> Vec<User> {
+        let tables = self.tables.load_full();
+        let mut users: Vec<User> =
+            on_rayon(|| tables.db.par_iter().map(|kv| kv.value().clone()).collect());
+        on_rayon(|| users.par_sort_unstable_by(|a, b| by.compare(order, a, b)));
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        users
+    }
+
+    /// The first `n` users of `list_sorted(key, order)`, found with a bounded
+    /// heap per rayon worker instead of sorting the whole table.
+    pub async fn top_n_by(&self, key: SortKey, order: Order, n: usize) -> Vec<User> {
+        let tables = self.tables.load_full();
+        if n == 0 {
+            return Vec::new();
+        }
+        let heap = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .fold(BinaryHeap::new, |mut heap, kv| {
+                    let user = kv.value();
+                    let admits = heap.len() < n
+                        || heap.peek().is_some_and(|worst: &Ranked| {
+                            key.compare(order, user, &worst.user) == std::cmp::Ordering::Less
+                        });
+                    if admits {
+                        heap.push(Ranked {
+                            user: user.clone(),
+                            key,
+                            order,
+                        });
+                        if heap.len() > n {
+                            heap.pop();
+                        }
+                    }
+                    heap
+                })
+                .reduce(BinaryHeap::new, |mut a, b| {
+                    for ranked in b {
+                        a.push(ranked);
+                        if a.len() > n {
+                            a.pop();
+                        }
+                    }
+                    a
+                })
+        });
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        heap.into_sorted_vec().into_iter().map(|r| r.user).collect()
+    }
+
+    /// Yields every user without cloning the whole table up front: only the
+    /// ids of one shard at a time are snapshotted, and each user is read when
+    /// the stream gets to it. Users deleted in the meantime are skipped;
+    /// users added to a shard the stream has not reached yet are included.
+    pub fn stream_users(&self) -> impl Stream<Item = User> + '_ {
+        let tables = self.tables.load_full();
+        stream::iter(0..tables.db.shards().len()).flat_map(move |shard| {
+            let tables = Arc::clone(&tables);
+            let ids = shard_keys(&tables.db, shard);
+            stream::iter(ids).filter_map(move |id| {
+                let user = tables.db.get(&id).map(|user| user.value().clone());
+                future::ready(user)
+            })
+        })
+    }
+
+    /// Buckets every user by `key_fn`, folding per rayon worker and merging
+    /// the partial maps. Each group holds full clones of its users, so this
+    /// copies the whole table; with high-cardinality keys the per-group
+    /// overhead can exceed the users themselves.
+    pub fn group_by<K, F>(&self, key_fn: F) -> HashMap<K, Vec<User>>
+    where
+        F: Fn(&User) -> K + Sync + Send,
+        K: Eq + std::hash::Hash + Send,
+    {
+        let tables = self.tables.load_full();
+        on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .fold(HashMap::new, |mut groups: HashMap<K, Vec<User>>, kv| {
+                    groups
+                        .entry(key_fn(kv.value()))
+                        .or_default()
+                        .push(kv.value().clone());
+                    groups
+                })
+                .reduce(HashMap::new, |mut merged, groups| {
+                    for (key, mut users) in groups {
+                        merged.entry(key).or_default().append(&mut users);
+                    }
+                    merged
+                })
+        })
+    }
+
+    /// `stream_users` grouped into vectors of `batch_size` (at least 1); the
+    /// last batch may be shorter.
+    pub fn stream_user_batches(&self, batch_size: usize) -> impl Stream<Item = Vec<User>> + '_ {
+        self.stream_users().chunks(batch_size.max(1))
+    }
+
+    /// An immutable copy of every user, cheap to share across tasks. Later
+    /// mutations of the service are not reflected in it.
+    pub async fn take_snapshot(&self) -> Arc<Vec<User>> {
+        let tables = self.tables.load_full();
+        let users: Vec<User> =
+            on_rayon(|| tables.db.par_iter().map(|kv| kv.value().clone()).collect());
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        Arc::new(users)
+    }
+
+    pub async fn snapshot_columns(&self) -> ColumnarSnapshot {
+        let tables = self.tables.load_full();
+        let len = tables.db.len();
+        let mut snapshot = ColumnarSnapshot {
+            ids: Vec::with_capacity(len),
+            names: Vec::with_capacity(len),
+            emails: Vec::with_capacity(len),
+            ages: Vec::with_capacity(len),
+            created_at: Vec::with_capacity(len),
+            updated_at: Vec::with_capacity(len),
+        };
+        for kv in tables.db.iter() {
+            let user = kv.value();
+            snapshot.ids.push(user.id.clone());
+            snapshot.names.push(user.name.clone());
+            snapshot.emails.push(user.email.clone());
+            snapshot.ages.push(user.age);
+            snapshot.created_at.push(user.created_at);
+            snapshot.updated_at.push(user.updated_at);
+        }
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        snapshot
+    }
+
+    pub async fn bulk_create_users(
+        self: Arc<Self>,
+        requests: Vec<CreateUserRequest>,
+    ) -> Result<Vec<Result<User, DatabaseError>>, DatabaseError> {
+        // Empty input is a no-op: no permit, no progress events, no stats.
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let _permit = self.bulk_permit().await;
+        self.check_bulk_size(requests.len())?;
+        let started_at = self.clock.now();
+        let start = Instant::now();
+
+        self.reporter.report(ProgressEvent::TransformStarted {
+            count: requests.len(),
+        });
+
+        let svc = Arc::clone(&self);
+        let (processed, keys): (Vec<_>, Vec<_>) = par_map_chunked(requests, move |req| {
+            let key = req.email.as_deref().map(|email| svc.email_key(email));
+            (uppercase_name(req), key)
+        })
+        .await?
+        .into_iter()
+        .unzip();
+        let kept_by = self.batch_duplicates(&keys)?;
+
+        self.reporter.report(ProgressEvent::TransformFinished);
+        if self.bulk_transactional {
+            self.prevalidate_batch(&processed, &keys, &kept_by)?;
+        }
+
+        let unique: Vec<_> = processed
+            .iter()
+            .zip(&kept_by)
+            .filter(|(_, kept)| kept.is_none())
+            .map(|(req, _)| req.clone())
+            .collect();
+        let mut created = Vec::with_capacity(unique.len());
+
+        for (i, batch) in unique.chunks(BULK_BATCH_SIZE).enumerate() {
+            self.reporter.report(ProgressEvent::BatchStarted {
+                batch: i + 1,
+                size: batch.len(),
+            });
+            created.extend(self.spawn_create_batch_unannounced(batch.to_vec()).await);
+            self.reporter
+                .report(ProgressEvent::BatchFinished { batch: i + 1 });
+        }
+
+        let mut created = created.into_iter();
+        let (mut results, replayed): (Vec<_>, Vec<_>) = kept_by
+            .into_iter()
+            .map(|kept| match kept {
+                Some(kept) => (Err(DatabaseError::DuplicateInBatch { kept }), false),
+                None => match created.next() {
+                    Some(Ok((user, replayed))) => (Ok(user), replayed),
+                    Some(Err(e)) => (Err(e), false),
+                    None => (Err(DatabaseError::TaskCancelled), false),
+                },
+            })
+            .unzip();
+        if self.bulk_input_order {
+            self.stamp_input_order(&mut results, started_at);
+        }
+        // Announced only now, so the events carry the stamped timestamps.
+        for (result, replayed) in results.iter().zip(replayed) {
+            if let Ok(user) = result
+                && !replayed
+            {
+                self.emit(UserEvent::Created(user.clone())).await;
+            }
+        }
+        if self.bulk_transactional {
+            self.rollback_failed_batch(&results).await?;
+        }
+
+        self.record_parallel_op(
+            ParallelOpKind::BulkCreate,
+            started_at,
+            start.elapsed(),
+            processed.len(),
+        )
+        .await;
+
+        self.reporter.report(ProgressEvent::BulkFinished {
+            batches: unique.len().div_ceil(BULK_BATCH_SIZE),
+        });
+        Ok(results)
+    }
+
+    /// The first phase of a transactional bulk create: every reason any
+    /// request would fail, without inserting anything.
+    fn prevalidate_batch(
+        &self,
+        requests: &[CreateUserRequest],
+        keys: &[Option<String>],
+        kept_by: &[Option<usize>],
+    ) -> Result<(), DatabaseError> {
+        let tables = self.tables.load_full();
+        let mut violations: Vec<(usize, String)> = on_rayon(|| {
+            requests
+                .par_iter()
+                .zip(keys)
+                .zip(kept_by)
+                .enumerate()
+                .filter_map(|(i, ((req, key), kept))| {
+                    let violation = match self.validate_fields(
+                        &req.name,
+                        req.email.as_deref(),
+                        req.age,
+                        req.country.as_deref(),
+                    ) {
+                        Err(e) => Some(e.to_string()),
+                        Ok(()) => match (kept, key) {
+                            (Some(kept), _) => {
+                                Some(DatabaseError::DuplicateInBatch { kept: *kept }.to_string())
+                            }
+                            (None, Some(key)) if tables.email_index.contains_key(key) => {
+                                Some(DatabaseError::UserAlreadyExists.to_string())
+                            }
+                            _ => None,
+                        },
+                    };
+                    violation.map(|msg| (i, msg))
+                })
+                .collect()
+        });
+        if violations.is_empty() {
+            return Ok(());
+        }
+        violations.sort_unstable_by_key(|(i, _)| *i);
+        Err(DatabaseError::BatchRejected { violations })
+    }
+
+    /// The second phase of a transactional bulk create: if any insert failed
+    /// despite prevalidation, for example because a concurrent create took
+    /// an email, deletes the users this call did insert and rejects it.
+    async fn rollback_failed_batch(
+        &self,
+        results: &[Result<User, DatabaseError>],
+    ) -> Result<(), DatabaseError> {
+        let violations: Vec<(usize, String)> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| result.as_ref().err().map(|e| (i, e.to_string())))
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        for user in results.iter().flatten() {
+            let _ = self.delete_user(&user.id).await;
+        }
+        Err(DatabaseError::BatchRejected { violations })
+    }
+
+    /// `bulk_create_users`, also reporting the change in service stats over
+    /// the run. Attribution is exact only while no other caller is using the
+    /// service; see `BulkCreateRun::stats_delta`.
+    pub async fn bulk_create_users_tracked(
+        self: Arc<Self>,
+        requests: Vec<CreateUserRequest>,
+    ) -> Result<BulkCreateRun, DatabaseError> {
+        let before = self.get_stats().await;
+        let results = Arc::clone(&self).bulk_create_users(requests).await?;
+        Ok(BulkCreateRun {
+            results,
+            stats_delta: self.stats_delta(&before),
+        })
+    }
+
+    /// Rewrites `created_at` and `sequence` of the created users so both
+    /// follow their position in `results`.
+    fn stamp_input_order(
+        &self,
+        results: &mut [Result<User, DatabaseError>],
+        created_at: chrono::DateTime<chrono::Utc>,
+    ) {
+        let tables = self.tables.load_full();
+        let base = self
+            .sequence
+            .fetch_add(results.len() as u64, Ordering::Relaxed);
+        on_rayon(|| {
+            results
+                .par_iter_mut()
+                .enumerate()
+                .filter_map(|(i, result)| result.as_mut().ok().map(|user| (i, user)))
+                .for_each(|(i, user)| {
+                    user.created_at = created_at;
+                    user.updated_at = created_at;
+                    user.sequence = base + i as u64;
+                    if let Some(mut stored) = tables.db.get_mut(&user.id) {
+                        stored.created_at = user.created_at;
+                        stored.updated_at = user.updated_at;
+                        stored.sequence = user.sequence;
+                    }
+                })
+        });
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// For each request, the index of the request with the same email key
+    /// that `bulk_duplicate_policy` keeps instead of it, or `None` if it is
+    /// the one kept.
+    fn batch_duplicates(
+        &self,
+        keys: &[Option<String>],
+    ) -> Result<Vec<Option<usize>>, DatabaseError> {
+        let mut kept: HashMap<&str, usize> = HashMap::with_capacity(keys.len());
+        let mut duplicates = 0;
+        for (i, key) in keys.iter().enumerate() {
+            let Some(key) = key else {
+                continue;
+            };
+            match kept.entry(key.as_str()) {
+                std::collections::hash_map::Entry::Occupied(mut slot) => {
+                    duplicates += 1;
+                    if self.bulk_duplicate_policy == DuplicateEmailPolicy::KeepLast {
+                        slot.insert(i);
+                    }
+                }
+                std::collections::hash_map::Entry::Vacant(slot) => {
+                    slot.insert(i);
+                }
+            }
+        }
+        if duplicates > 0 && self.bulk_duplicate_policy == DuplicateEmailPolicy::Reject {
+            return Err(DatabaseError::ValidationError(format!(
+                "{} requests repeat an email already in the batch",
+                duplicates
+            )));
+        }
+        Ok(keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                key.as_ref()
+                    .map(|key| kept[key.as_str()])
+                    .filter(|&k| k != i)
+            })
+            .collect())
+    }
+
+    /// Like `bulk_create_users`, but pulls requests lazily so at most one batch
+    /// is materialized at a time.
+    pub async fn bulk_create_from_iter<I>(self: Arc<Self>, requests: I) -> BulkCreateSummary
+    where
+        I: IntoIterator<Item = CreateUserRequest>,
+    {
+        let mut requests = requests.into_iter().peekable();
+        let mut summary = BulkCreateSummary::default();
+        if requests.peek().is_none() {
+            return summary;
+        }
+        let _permit = self.bulk_permit().await;
+        let started_at = self.clock.now();
+        let start = Instant::now();
+        let mut batch_no = 0;
+
+        loop {
+            let batch: Vec<_> = requests.by_ref().take(BULK_BATCH_SIZE).collect();
+            if batch.is_empty() {
+                break;
+            }
+            batch_no += 1;
+
+            let size = batch.len();
+            let Ok(processed) = par_map_chunked(batch, uppercase_name).await else {
+                summary.failed += size;
+                continue;
+            };
+            self.reporter.report(ProgressEvent::BatchStarted {
+                batch: batch_no,
+                size: processed.len(),
+            });
+
+            for result in self.spawn_create_batch(processed).await {
+                match result {
+                    Ok(_) => summary.created += 1,
+                    Err(_) => summary.failed += 1,
+                }
+            }
+            self.reporter
+                .report(ProgressEvent::BatchFinished { batch: batch_no });
+        }
+
+        self.record_parallel_op(
+            ParallelOpKind::BulkCreateFromIter,
+            started_at,
+            start.elapsed(),
+            summary.created + summary.failed,
+        )
+        .await;
+
+        self.reporter
+            .report(ProgressEvent::BulkFinished { batches: batch_no });
+        summary
+    }
+
+    fn check_bulk_size(&self, size: usize) -> Result<(), DatabaseError> {
+        if size > self.max_bulk_size {
+            return Err(DatabaseError::InputTooLarge {
+                size,
+                max: self.max_bulk_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts `workers` tasks that create users from the returned channel.
+    /// The channel is bounded, so senders wait when the workers fall behind.
+    /// Dropping every sender lets the workers drain what is queued and stop;
+    /// the handle resolves once they have.
+    pub fn spawn_ingest_workers(
+        self: Arc<Self>,
+        workers: usize,
+    ) -> (mpsc::Sender<CreateUserRequest>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(INGEST_CHANNEL_CAPACITY);
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        let mut tasks = JoinSet::new();
+        for _ in 0..workers.max(1) {
+            let svc = Arc::clone(&self);
+            let rx = Arc::clone(&rx);
+            tasks.spawn(async move {
+                loop {
+                    let Some(req) = rx.lock().await.recv().await else {
+                        break;
+                    };
+                    let _ = svc.create_user(req).await;
+                }
+            });
+        }
+        let handle = tokio::spawn(async move { while tasks.join_next().await.is_some() {} });
+        (tx, handle)
+    }
+
+    /// Starts one create per tick of a `1 / target_per_sec` interval instead
+    /// of all at once, for backfills that should not saturate the machine.
+    /// Creates may still overlap when one takes longer than a tick.
+    pub async fn bulk_create_throttled(
+        self: Arc<Self>,
+        reqs: Vec<CreateUserRequest>,
+        target_per_sec: u32,
+    ) -> Result<ThrottledSummary, DatabaseError> {
+        let _permit = self.bulk_permit().await;
+        self.check_bulk_size(reqs.len())?;
+        if target_per_sec == 0 {
+            return Err(DatabaseError::ValidationError(
+                "Target rate must be at least 1 per second".to_string(),
+            ));
+        }
+        let start = Instant::now();
+        let count = reqs.len();
+        let mut ticker = tokio::time::interval(Duration::from_secs(1) / target_per_sec);
+        let mut tasks = JoinSet::new();
+        let mut summary = ThrottledSummary::default();
+
+        let tally = |summary: &mut ThrottledSummary, result| match result {
+            Ok(Ok(_)) => summary.created += 1,
+            _ => summary.failed += 1,
+        };
+
+        for req in reqs {
+            ticker.tick().await;
+            let svc = Arc::clone(&self);
+            tasks.spawn(async move { svc.create_user(uppercase_name(req)).await });
+            while let Some(result) = tasks.try_join_next() {
+                tally(&mut summary, result);
+            }
+        }
+        let paced = start.elapsed();
+        while let Some(result) = tasks.join_next().await {
+            tally(&mut summary, result);
+        }
+
+        summary.duration = start.elapsed();
+        summary.achieved_per_sec = if paced.is_zero() {
+            count as f64
+        } else {
+            count as f64 / paced.as_secs_f64()
+        };
+        self.reporter.report(ProgressEvent::ThrottledFinished {
+            created: summary.created,
+            failed: summary.failed,
+            duration: summary.duration,
+            achieved_per_sec: summary.achieved_per_sec,
+        });
+        Ok(summary)
+    }
+
+    /// Inserts lazily pulled requests while keeping the estimated bytes of
+    /// in-flight requests under `memory_budget`. Intake pauses once the budget
+    /// is used up and resumes as inserts complete. A single request larger
+    /// than the whole budget is still admitted, on its own.
+    pub async fn bulk_create_with_budget<I>(
+        self: Arc<Self>,
+        requests: I,
+        memory_budget: usize,
+    ) -> BulkCreateSummary
+    where
+        I: IntoIterator<Item = CreateUserRequest>,
+    {
+        let _permit = self.bulk_permit().await;
+        let started_at = self.clock.now();
+        let start = Instant::now();
+        let budget = memory_budget.clamp(1, u32::MAX as usize) as u32;
+        let permits = Arc::new(Semaphore::new(budget as usize));
+        let mut tasks = JoinSet::new();
+        let mut summary = BulkCreateSummary::default();
+
+        let tally = |summary: &mut BulkCreateSummary, result| match result {
+            Ok(Ok(_)) => summary.created += 1,
+            _ => summary.failed += 1,
+        };
+
+        for req in requests {
+            let cost = req.estimated_size().clamp(1, budget as usize) as u32;
+            let permit = Arc::clone(&permits)
+                .acquire_many_owned(cost)
+                .await
+                .expect("budget semaphore is never closed");
+            let svc = Arc::clone(&self);
+            tasks.spawn(async move {
+                let result = svc.create_user(uppercase_name(req)).await;
+                drop(permit);
+                result
+            });
+            while let Some(result) = tasks.try_join_next() {
+                tally(&mut summary, result);
+            }
+        }
+        while let Some(result) = tasks.join_next().await {
+            tally(&mut summary, result);
+        }
+
+        self.record_parallel_op(
+            ParallelOpKind::BulkCreateBudgeted,
+            started_at,
+            start.elapsed(),
+            summary.created + summary.failed,
+        )
+        .await;
+        summary
+    }
+
+    /// Inserts one batch using the configured `BulkStrategy`. Results are in
+    /// input order whatever the strategy.
+    async fn spawn_create_batch(
+        self: &Arc<Self>,
+        batch: Vec<CreateUserRequest>,
+    ) -> Vec<Result<User, DatabaseError>> {
+        let results = self.spawn_create_batch_unannounced(batch).await;
+        let mut created = Vec::with_capacity(results.len());
+        for result in results {
+            if let Ok((user, false)) = &result {
+                self.emit(UserEvent::Created(user.clone())).await;
+            }
+            created.push(result.map(|(user, _)| user));
+        }
+        created
+    }
+
+    /// `spawn_create_batch` without the `Created` events; each user comes
+    /// with whether it was an idempotency-key replay.
+    async fn spawn_create_batch_unannounced(
+        self: &Arc<Self>,
+        batch: Vec<CreateUserRequest>,
+    ) -> Vec<Result<(User, bool), DatabaseError>> {
+        match self.bulk_strategy {
+            BulkStrategy::SpawnPerTask => {
+                let tasks = batch.into_iter().map(|req| {
+                    let svc = Arc::clone(self);
+                    tokio::spawn(async move {
+                        svc.report_creating(&req);
+                        svc.create_user_unannounced(req).await
+                    })
+                });
+                join_tasks(tasks).await
+            }
+            BulkStrategy::Bounded(max_in_flight) => {
+                let permits = Arc::new(Semaphore::new(max_in_flight.max(1)));
+                let tasks = batch.into_iter().map(|req| {
+                    let svc = Arc::clone(self);
+                    let permits = Arc::clone(&permits);
+                    tokio::spawn(async move {
+                        let _permit = permits
+                            .acquire_owned()
+                            .await
+                            .expect("bulk semaphore is never closed");
+                        svc.report_creating(&req);
+                        svc.create_user_unannounced(req).await
+                    })
+                });
+                join_tasks(tasks).await
+            }
+            BulkStrategy::RayonBlocking => {
+                // The per-request delays would all overlap, so the batch
+                // waits once for the same simulated validation latency.
+                sleep(VALIDATION_DELAY).await;
+                let len = batch.len();
+                let svc = Arc::clone(self);
+                tokio::task::spawn_blocking(move || {
+                    let create = |req: CreateUserRequest| {
+                        svc.report_creating(&req);
+                        svc.create_user_blocking(req)
+                    };
+                    on_rayon(|| batch.into_par_iter().map(create).collect())
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    // The whole batch shares one blocking task, so every item
+                    // inherits its failure.
+                    let msg = DatabaseError::from(e).to_string();
+                    (0..len)
+                        .map(|_| Err(DatabaseError::TaskPanicked(msg.clone())))
+                        .collect()
+                })
+            }
+        }
+    }
+
+    fn report_creating(&self, req: &CreateUserRequest) {
+        if self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        self.reporter.report(ProgressEvent::CreatingUser {
+            name: req.name.clone(),
+        });
+    }
+
+    pub async fn search_users_parallel(&self, query: &str) -> Result<Vec<User>, DatabaseError> {
+        self.search_users_with(query, SearchOptions::default())
+            .await
+    }
+
+    /// `search_users_parallel` with the matching rules of `options`.
+    pub async fn search_users_with(
+        &self,
+        query: &str,
+        options: SearchOptions,
+    ) -> Result<Vec<User>, DatabaseError> {
+        let query = options.normalize(query);
+        // Read before scanning, so a mutation during the scan leaves the
+        // cached result stale rather than labelled current.
+        let generation = self.generation.load(Ordering::Relaxed);
+        if let Some(cache) = &self.search_cache
+            && let Some(users) = cache.get(&query, options, generation, self.clock.now())
+        {
+            self.increment_stat(|stats| stats.read_count += 1).await;
+            return Ok(users);
+        }
+        let users = self.list_users().await?;
+        let results: Vec<User> = on_rayon(|| {
+            users
+                .into_par_iter()
+                .filter(|user| matches_query(user, &query, options))
+                .collect()
+        });
+        if let Some(cache) = &self.search_cache {
+            cache.put(CachedSearch {
+                query,
+                fold_diacritics: options.fold_diacritics,
+                generation,
+                cached_at: self.clock.now(),
+                users: results.clone(),
+            });
+        }
+        Ok(results)
+    }
+
+    /// Like `search_users_parallel`, but yields matches as rayon finds them
+    /// through a bounded channel, so a slow consumer pauses the search. No
+    /// map guard is held while waiting on the consumer. Dropping the stream
+    /// stops the search.
+    pub fn search_stream(&self, query: &str) -> impl Stream<Item = User> + use<> {
+        let tables = self.tables.load_full();
+        let query = query.to_lowercase();
+        let (tx, mut rx) = mpsc::channel(SEARCH_STREAM_CAPACITY);
+        tokio::task::spawn_blocking(move || {
+            let ids: Vec<String> = tables.db.iter().map(|kv| kv.key().clone()).collect();
+            let _ = on_rayon(|| {
+                ids.par_chunks(BULK_BATCH_SIZE).try_for_each(|chunk| {
+                    let matches: Vec<User> = chunk
+                        .iter()
+                        .filter_map(|id| tables.db.get(id).map(|user| user.value().clone()))
+                        .filter(|user| matches_query(user, &query, SearchOptions::default()))
+                        .collect();
+                    matches
+                        .into_iter()
+                        .try_for_each(|user| tx.blocking_send(user).map_err(|_| ()))
+                })
+            });
+        });
+        stream::poll_fn(move |cx| rx.poll_recv(cx))
+    }
+
+    /// Returns a user matching `pred`, stopping as soon as any rayon worker
+    /// finds one. When several users match, which one is returned is
+    /// nondeterministic.
+    pub async fn find_first<F>(&self, pred: F) -> Option<User>
+    where
+        F: Fn(&User) -> bool + Sync + Send,
+    {
+        let tables = self.tables.load_full();
+        let found = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .find_any(|kv| pred(kv.value()))
+                .map(|kv| kv.value().clone())
+        });
+        self.increment_stat(|stats| stats.read_count += 1).await;
+        found
+    }
+
+    pub async fn fast_concurrent_operations(self: Arc<Self>) -> Result<(), DatabaseError> {
+        self.reporter
+            .report(ProgressEvent::FastOpsStarted { count: 5 });
+        let start = Instant::now();
+
+        let reqs = (0..5)
+            .map(|i| CreateUserRequest {
+                name: format!("Fast User {}", i),
+                email: Some(format!("fast{}@demo.com", i)),
+                age: Some(20 + i as u8),
+                idempotency_key: None,
+                country: None,
+            })
+            .collect::<Vec<_>>();
+
+        let handles = reqs.into_iter().map(|req| {
+            let service = Arc::clone(&self);
+            async move { service.create_user(req).await }
+        });
+
+        let results = future::join_all(handles).await;
+
+        let duration = start.elapsed();
+        if self.verbosity == Verbosity::Normal {
+            for (i, result) in results.into_iter().enumerate() {
+                self.reporter.report(ProgressEvent::FastTaskFinished {
+                    task: i,
+                    created: result.ok().map(|user| user.name),
+                });
+            }
+        }
+        self.reporter
+            .report(ProgressEvent::FastOpsFinished { duration });
+        Ok(())
+    }
+
+    pub async fn bulk_insert_concurrent(
+        self: Arc<Self>,
+        count: usize,
+    ) -> Result<(), DatabaseError> {
+        if count == 0 {
+            return Ok(());
+        }
+        let _permit = self.bulk_permit().await;
+        self.check_bulk_size(count)?;
+        self.reporter
+            .report(ProgressEvent::BulkInsertStarted { count });
+        let start = Instant::now();
+
+        let requests: Vec<_> = (0..count)
+            .map(|i| CreateUserRequest {
+                name: format!("BulkConcurrent {}", i),
+                email: Some(format!("bulk{}@demo.com", i)),
+                age: Some(20 + (i % 80) as u8),
+                idempotency_key: None,
+                country: None,
+            })
+            .collect();
+
+        let processed = par_map_chunked(requests, uppercase_name).await?;
+
+        let handles = processed.into_iter().map(|req| {
+            let service = Arc::clone(&self);
+            async move { service.create_user(req).await }
+        });
 
         let results = future::join_all(handles).await;
         let success = results.iter().filter(|r| r.is_ok()).count();
         let duration = start.elapsed();
 
-        println!("✅ Inserted {} users in {:?}", success, duration);
-        Ok(())
+        self.reporter.report(ProgressEvent::BulkInsertFinished {
+            inserted: success,
+            duration,
+        });
+        Ok(())
+    }
+
+    pub async fn bulk_save_to_csv(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save(path, Format::Csv).await
+    }
+
+    pub async fn bulk_save_to_csv_with(
+        &self,
+        path: &str,
+        options: &CsvOptions,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save_with(path, Format::Csv, options, JsonOptions::default())
+            .await
+    }
+
+    pub async fn bulk_save_to_json(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save(path, Format::Json).await
+    }
+
+    pub async fn bulk_save_to_ndjson(
+        &self,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save(path, Format::Ndjson).await
+    }
+
+    /// Like `save` for `Format::Json` or `Format::Ndjson`, with `options`
+    /// controlling the shape of each user.
+    pub async fn bulk_save_to_json_with(
+        &self,
+        path: &str,
+        format: Format,
+        options: JsonOptions,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save_with(path, format, &CsvOptions::default(), options)
+            .await
+    }
+
+    /// Writes every user to `path` in the given format.
+    pub async fn save(
+        &self,
+        path: &str,
+        format: Format,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.save_with(path, format, &CsvOptions::default(), JsonOptions::default())
+            .await
+    }
+
+    async fn save_with(
+        &self,
+        path: &str,
+        format: Format,
+        csv_options: &CsvOptions,
+        json_options: JsonOptions,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let start = Instant::now();
+        let users = self.list_users().await?;
+        let serialize_start = Instant::now();
+
+        let serialized_data = format.encode(&users, csv_options, json_options)?;
+        let write_start = Instant::now();
+
+        self.write_with_retry(path, &serialized_data).await?;
+
+        let duration = start.elapsed();
+        let count = users.len();
+
+        self.reporter.report(ProgressEvent::Saved {
+            format,
+            count,
+            path: path.to_string(),
+            duration,
+            serialize: serialize_start.elapsed(),
+            write: write_start.elapsed(),
+        });
+        Ok(())
+    }
+
+    /// Writes `data` to a temporary file next to `path` and renames it over
+    /// `path`, repeating the whole sequence per `save_retry`. A failed
+    /// attempt never leaves a partial file at `path`.
+    async fn write_with_retry(&self, path: &str, data: &[u8]) -> Result<(), DatabaseError> {
+        let tmp_path = format!("{}.tmp", path);
+        let max_attempts = self.save_retry.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let mut file = File::create(&tmp_path).await?;
+                file.write_all(data).await?;
+                file.flush().await?;
+                file.sync_all().await?;
+                tokio::fs::rename(&tmp_path, path).await
+            }
+            .await;
+            let Err(e) = result else {
+                return Ok(());
+            };
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            if attempt >= max_attempts {
+                return Err(DatabaseError::Io(e));
+            }
+            self.reporter.report(ProgressEvent::Retry { attempt });
+            sleep(self.save_retry.delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Writes only `fields`, in that order, as CSV. Rows are encoded in
+    /// parallel in `BULK_BATCH_SIZE` chunks and concatenated after the header.
+    pub async fn export_projection_csv(
+        &self,
+        path: &str,
+        fields: &[Field],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if fields.is_empty() {
+            return Err(Box::new(DatabaseError::ValidationError(
+                "Projection needs at least one field".to_string(),
+            )));
+        }
+        let start = Instant::now();
+        let users = self.list_users().await?;
+        let serialize_start = Instant::now();
+
+        let options = CsvOptions::default();
+        let mut header = options.writer_builder().from_writer(vec![]);
+        header.write_record(fields.iter().map(|field| field.header()))?;
+        let mut out = header.into_inner().map_err(|e| e.into_error())?;
+        let chunks = on_rayon(|| {
+            users
+                .par_chunks(BULK_BATCH_SIZE)
+                .map(|chunk| {
+                    let mut wtr = options
+                        .writer_builder()
+                        .has_headers(false)
+                        .from_writer(vec![]);
+                    for user in chunk {
+                        wtr.write_record(fields.iter().map(|field| field.value(user)))?;
+                    }
+                    wtr.into_inner().map_err(|e| e.into_error().into())
+                })
+                .collect::<Result<Vec<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>>>()
+        })?;
+        for chunk in chunks {
+            out.extend(chunk);
+        }
+        let write_start = Instant::now();
+
+        let mut file = File::create(path).await?;
+        file.write_all(&out).await?;
+        file.flush().await?;
+
+        self.reporter.report(ProgressEvent::Saved {
+            format: Format::Csv,
+            count: users.len(),
+            path: path.to_string(),
+            duration: start.elapsed(),
+            serialize: serialize_start.elapsed(),
+            write: write_start.elapsed(),
+        });
+        Ok(())
+    }
+
+    /// Saves a CSV export as `users-<timestamp>.snap` in `dir`, then deletes
+    /// the oldest snapshots beyond `snapshot_retention`. Returns the new file.
+    pub async fn save_snapshot(
+        &self,
+        dir: &str,
+    ) -> Result<std::path::PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        tokio::fs::create_dir_all(dir).await?;
+        let name = format!(
+            "users-{}.snap",
+            self.clock.now().format("%Y%m%dT%H%M%S%.6fZ")
+        );
+        let path = std::path::Path::new(dir).join(name);
+        self.save(&path.to_string_lossy(), Format::Csv).await?;
+
+        let snapshots = list_snapshots(dir).await?;
+        let excess = snapshots.len().saturating_sub(self.snapshot_retention);
+        for old in &snapshots[..excess] {
+            tokio::fs::remove_file(old).await?;
+        }
+        Ok(path)
+    }
+
+    /// Replaces the store with the newest snapshot in `dir`.
+    pub async fn load_latest_snapshot(
+        self: Arc<Self>,
+        dir: &str,
+    ) -> Result<RestoreSummary, Box<dyn std::error::Error + Send + Sync>> {
+        let _permit = self.bulk_permit().await;
+        let Some(latest) = list_snapshots(dir).await?.pop() else {
+            return Err(Box::new(DatabaseError::ValidationError(format!(
+                "No snapshots in {}",
+                dir
+            ))));
+        };
+        self.restore_csv_with_permit(&latest.to_string_lossy(), RestorePolicy::Overwrite)
+            .await
+    }
+
+    /// Writes every user as CSV row by row through a buffered writer,
+    /// optionally gzip-compressed, so memory stays flat regardless of table
+    /// size. Rows are pulled from `stream_users`.
+    pub async fn stream_save_csv(
+        &self,
+        path: &str,
+        gzip: bool,
+    ) -> Result<StreamSaveReport, Box<dyn std::error::Error + Send + Sync>> {
+        const ROWS_PER_CHUNK: usize = 1024;
+        let start = Instant::now();
+
+        let file = BufWriter::new(File::create(path).await?);
+        let mut out: Box<dyn AsyncWrite + Unpin + Send> = if gzip {
+            Box::new(GzipEncoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        let mut rows = 0;
+        let mut chunks = std::pin::pin!(self.stream_users().chunks(ROWS_PER_CHUNK));
+        while let Some(chunk) = chunks.next().await {
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(rows == 0)
+                .from_writer(Vec::new());
+            for user in &chunk {
+                wtr.serialize(UserCsvRecord::from(user))?;
+            }
+            out.write_all(&wtr.into_inner().map_err(|e| e.into_error())?)
+                .await?;
+            rows += chunk.len();
+        }
+        out.shutdown().await?;
+
+        let report = StreamSaveReport {
+            rows,
+            bytes_written: tokio::fs::metadata(path).await?.len(),
+            duration: start.elapsed(),
+        };
+        self.reporter.report(ProgressEvent::StreamSaved {
+            path: path.to_string(),
+            rows: report.rows,
+            bytes_written: report.bytes_written,
+            duration: report.duration,
+        });
+        Ok(report)
+    }
+
+    /// Splits the table into `shards` CSV files under `dir`, partitioned by a
+    /// hash of the user id. Each file carries its own header, so any of them
+    /// can be loaded on its own. Returns the number of users per shard.
+    pub async fn export_sharded_csv(
+        &self,
+        dir: &str,
+        shards: usize,
+    ) -> Result<Vec<usize>, Box<dyn std::error::Error + Send + Sync>> {
+        let tables = self.tables.load_full();
+        if shards == 0 {
+            return Err(Box::new(DatabaseError::ValidationError(
+                "Shard count must be at least 1".to_string(),
+            )));
+        }
+        let start = Instant::now();
+
+        let partitions = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .fold(
+                    || vec![Vec::new(); shards],
+                    |mut acc, kv| {
+                        acc[shard_for(kv.key(), shards)].push(UserCsvRecord::from(kv.value()));
+                        acc
+                    },
+                )
+                .reduce(
+                    || vec![Vec::new(); shards],
+                    |mut a, b| {
+                        for (into, from) in a.iter_mut().zip(b) {
+                            into.extend(from);
+                        }
+                        a
+                    },
+                )
+        });
+        self.increment_stat(|stats| stats.read_count += 1).await;
+
+        tokio::fs::create_dir_all(dir).await?;
+        let writes = partitions.into_iter().enumerate().map(|(i, records)| {
+            let path = std::path::Path::new(dir).join(format!("users_shard_{}.csv", i));
+            async move {
+                let mut wtr = csv::Writer::from_writer(vec![]);
+                for record in &records {
+                    wtr.serialize(record)?;
+                }
+                let data = wtr.into_inner().map_err(|e| e.into_error())?;
+                let mut file = File::create(&path).await?;
+                file.write_all(&data).await?;
+                file.flush().await?;
+                Ok::<usize, Box<dyn std::error::Error + Send + Sync>>(records.len())
+            }
+        });
+        let counts = future::try_join_all(writes).await?;
+
+        self.reporter.report(ProgressEvent::ShardedCsvSaved {
+            dir: dir.to_string(),
+            counts: counts.clone(),
+            duration: start.elapsed(),
+        });
+        Ok(counts)
+    }
+
+    pub async fn bulk_load_from_csv(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load(path, Format::Csv).await
+    }
+
+    pub async fn bulk_load_from_csv_with(
+        self: Arc<Self>,
+        path: &str,
+        options: &CsvOptions,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load_with(path, Format::Csv, options, DuplicateEmailPolicy::KeepFirst)
+            .await
+    }
+
+    pub async fn bulk_load_from_json(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load(path, Format::Json).await
+    }
+
+    pub async fn bulk_load_from_ndjson(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load(path, Format::Ndjson).await
+    }
+
+    /// Creates a new user for every record in `path`. Stored ids and
+    /// timestamps in the file are ignored. Rows repeating an earlier row's
+    /// email are skipped and listed in the summary.
+    pub async fn load(
+        self: Arc<Self>,
+        path: &str,
+        format: Format,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load_with_policy(path, format, DuplicateEmailPolicy::KeepFirst)
+            .await
+    }
+
+    pub async fn load_with_policy(
+        self: Arc<Self>,
+        path: &str,
+        format: Format,
+        policy: DuplicateEmailPolicy,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        self.load_with(path, format, &CsvOptions::default(), policy)
+            .await
+    }
+
+    async fn load_with(
+        self: Arc<Self>,
+        path: &str,
+        format: Format,
+        csv_options: &CsvOptions,
+        policy: DuplicateEmailPolicy,
+    ) -> Result<LoadSummary, Box<dyn std::error::Error + Send + Sync>> {
+        let _permit = self.bulk_permit().await;
+        let tables = self.tables.load_full();
+        let start = Instant::now();
+
+        let mut file = File::open(path).await?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+        let read_duration = start.elapsed();
+
+        let parse_start = Instant::now();
+        let rows = format.decode_rows(contents, csv_options)?;
+        let parse_duration = parse_start.elapsed();
+
+        self.reporter.report(ProgressEvent::LoadStarted {
+            format,
+            count: rows.len(),
+            path: path.to_string(),
+            read: read_duration,
+            parse: parse_duration,
+        });
+
+        let row_count = rows.len();
+        let rows: Vec<_> = rows
+            .into_iter()
+            .filter(|row| {
+                row.id
+                    .as_deref()
+                    .is_none_or(|id| self.validate_id(id).is_ok())
+            })
+            .collect();
+        let malformed_ids = row_count - rows.len();
+
+        let (requests, duplicate_emails) = self.resolve_duplicate_emails(rows, policy);
+        if !duplicate_emails.is_empty() {
+            self.reporter.report(ProgressEvent::DuplicateEmailsFound {
+                path: path.to_string(),
+                count: duplicate_emails.len(),
+                policy,
+            });
+            if policy == DuplicateEmailPolicy::Reject {
+                let emails: Vec<_> = duplicate_emails.iter().map(|d| d.email.as_str()).collect();
+                return Err(Box::new(DatabaseError::ValidationError(format!(
+                    "Duplicate emails in {}: {}",
+                    path,
+                    emails.join(", ")
+                ))));
+            }
+        }
+
+        self.reserve(requests.len());
+        let insert_start = Instant::now();
+        let handles = requests.into_iter().map(|req| {
+            let service = Arc::clone(&self);
+            tokio::spawn(async move { service.create_user(req).await })
+        });
+
+        let mut summary = LoadSummary {
+            failed: malformed_ids,
+            duplicate_emails,
+            ..LoadSummary::default()
+        };
+        for result in futures::future::join_all(handles).await {
+            match result {
+                Ok(Ok(_)) => summary.loaded += 1,
+                _ => summary.failed += 1,
+            }
+        }
+        let total_duration = start.elapsed();
+
+        self.reporter.report(ProgressEvent::Loaded {
+            format,
+            count: tables.db.len(),
+            path: path.to_string(),
+            duration: total_duration,
+            insert: insert_start.elapsed(),
+        });
+        Ok(summary)
+    }
+
+    /// Imports a messy CSV in stages: parse, drop later rows repeating an
+    /// email (keep-first), validate the rest on the rayon pool, then insert
+    /// the survivors in `BULK_BATCH_SIZE` batches with the configured
+    /// `BulkStrategy`. Rows get fresh ids, as with `bulk_load_from_csv`.
+    pub async fn import_csv_clean(
+        self: Arc<Self>,
+        path: &str,
+    ) -> Result<ImportReport, Box<dyn std::error::Error + Send + Sync>> {
+        let _permit = self.bulk_permit().await;
+        let contents = tokio::fs::read(path).await?;
+        let rows = Format::Csv.decode_rows(contents, &CsvOptions::default())?;
+        let mut report = ImportReport {
+            parsed: rows.len(),
+            ..ImportReport::default()
+        };
+
+        let (requests, _) = self.resolve_duplicate_emails(rows, DuplicateEmailPolicy::KeepFirst);
+        report.deduped = report.parsed - requests.len();
+
+        let verdicts = self.validate_batch(&requests);
+        let valid: Vec<CreateUserRequest> = requests
+            .into_iter()
+            .zip(verdicts)
+            .filter_map(|(req, verdict)| verdict.is_ok().then_some(req))
+            .collect();
+        report.invalid = report.parsed - report.deduped - valid.len();
+
+        self.reserve(valid.len());
+        for batch in valid.chunks(BULK_BATCH_SIZE) {
+            for result in self.spawn_create_batch(batch.to_vec()).await {
+                match result {
+                    Ok(_) => report.inserted += 1,
+                    Err(_) => report.failed += 1,
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Restores a CSV export keeping its ids and timestamps, resolving rows
+    /// whose id is already stored according to `policy`. Rows without an id
+    /// get a fresh one. Restored rows are emitted as `Created` or `Updated`
+    /// events once the whole file is stored; the users dropped by
+    /// `RestorePolicy::Overwrite` produce no `Deleted` events.
+    pub async fn restore_from_csv(
+        self: Arc<Self>,
+        path: &str,
+        policy: RestorePolicy,
+    ) -> Result<RestoreSummary, Box<dyn std::error::Error + Send + Sync>> {
+        let _permit = self.bulk_permit().await;
+        self.restore_csv_with_permit(path, policy).await
+    }
+
+    /// `restore_from_csv` for a caller already holding a bulk permit.
+    async fn restore_csv_with_permit(
+        self: Arc<Self>,
+        path: &str,
+        policy: RestorePolicy,
+    ) -> Result<RestoreSummary, Box<dyn std::error::Error + Send + Sync>> {
+        let tables = self.tables.load_full();
+        if policy == RestorePolicy::FailIfNotEmpty && !tables.db.is_empty() {
+            return Err(Box::new(DatabaseError::ValidationError(format!(
+                "Refusing to restore {} into a store holding {} users",
+                path,
+                tables.db.len()
+            ))));
+        }
+
+        let start = Instant::now();
+        let mut file = File::open(path).await?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+        let rows = Format::Csv.decode_rows(contents, &CsvOptions::default())?;
+        self.reserve(rows.len());
+
+        let insert_start = Instant::now();
+        let svc = Arc::clone(&self);
+        let emits = self.has_event_consumers();
+        let (summary, events) = tokio::task::spawn_blocking(move || {
+            if policy == RestorePolicy::Overwrite {
+                svc.clear();
+            }
+            let mut summary = RestoreSummary::default();
+            let mut events = Vec::new();
+            for row in rows {
+                match svc.restore_row(row, policy) {
+                    Ok(MergeOutcome::Skipped) => summary.skipped += 1,
+                    Ok(outcome) => {
+                        summary.restored += 1;
+                        if emits {
+                            events.extend(outcome.into_event());
+                        }
+                    }
+                    Err(_) => summary.failed += 1,
+                }
+            }
+            (summary, events)
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+        for event in events {
+            self.emit(event).await;
+        }
+
+        self.reporter.report(ProgressEvent::Loaded {
+            format: Format::Csv,
+            count: tables.db.len(),
+            path: path.to_string(),
+            duration: start.elapsed(),
+            insert: insert_start.elapsed(),
+        });
+        Ok(summary)
+    }
+
+    /// Reads full `User` records from NDJSON, as written by
+    /// `bulk_save_to_ndjson`, and upserts each by id, keeping whichever of
+    /// the stored and incoming user was updated last. Lines are applied in
+    /// `BULK_BATCH_SIZE` batches on the rayon pool, one batch at a time, so
+    /// at most one batch is buffered however long the feed is. Blank lines
+    /// are ignored. Each failed line is reported as `MergeLineFailed` with
+    /// its line number and reason.
+    pub async fn merge_import_ndjson_stream<R>(
+        self: Arc<Self>,
+        reader: R,
+    ) -> Result<MergeImportSummary, DatabaseError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let _permit = self.bulk_permit().await;
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let mut summary = MergeImportSummary::default();
+        let mut batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        let mut line_numbers = Vec::with_capacity(BULK_BATCH_SIZE);
+        let mut line_number = 0;
+        loop {
+            let line = lines.next_line().await.map_err(DatabaseError::Io)?;
+            let done = line.is_none();
+            if let Some(line) = line {
+                line_number += 1;
+                if !line.trim().is_empty() {
+                    batch.push(line);
+                    line_numbers.push(line_number);
+                }
+            }
+            if batch.len() == BULK_BATCH_SIZE || (done && !batch.is_empty()) {
+                let svc = Arc::clone(&self);
+                let lines = std::mem::replace(&mut batch, Vec::with_capacity(BULK_BATCH_SIZE));
+                let outcomes: Vec<Result<MergeOutcome, DatabaseError>> =
+                    tokio::task::spawn_blocking(move || on_rayon(|| svc.merge_batch(&lines)))
+                        .await?;
+                let emits = self.has_event_consumers();
+                for (outcome, line) in outcomes.into_iter().zip(line_numbers.drain(..)) {
+                    let outcome = match outcome {
+                        Ok(outcome) => outcome,
+                        Err(e) => {
+                            summary.failed += 1;
+                            self.reporter.report(ProgressEvent::MergeLineFailed {
+                                line,
+                                reason: e.to_string(),
+                            });
+                            continue;
+                        }
+                    };
+                    match &outcome {
+                        MergeOutcome::Created(_) => summary.created += 1,
+                        MergeOutcome::Replaced(_) => summary.updated += 1,
+                        MergeOutcome::Skipped => summary.skipped += 1,
+                    }
+                    if emits && let Some(event) = outcome.into_event() {
+                        self.emit(event).await;
+                    }
+                }
+            }
+            if done {
+                return Ok(summary);
+            }
+        }
+    }
+
+    /// Merges one batch of NDJSON lines, with an outcome per line. Lines
+    /// sharing an id are resolved before anything is stored: only the newest
+    /// of them (the first on a tie) is merged and the rest count as skipped,
+    /// so the stored version does not depend on which thread ran first.
+    fn merge_batch(&self, lines: &[String]) -> Vec<Result<MergeOutcome, DatabaseError>> {
+        let parsed: Vec<Result<User, DatabaseError>> = lines
+            .par_iter()
+            .map(|line| self.parse_merge_line(line))
+            .collect();
+        let mut newest: HashMap<&str, usize> = HashMap::new();
+        for (i, user) in parsed.iter().enumerate() {
+            let Ok(user) = user else { continue };
+            let kept = newest.entry(&user.id).or_insert(i);
+            if let Ok(current) = &parsed[*kept]
+                && current.updated_at < user.updated_at
+            {
+                *kept = i;
+            }
+        }
+        let mut merged = vec![false; parsed.len()];
+        for i in newest.into_values() {
+            merged[i] = true;
+        }
+        parsed
+            .into_par_iter()
+            .zip(merged)
+            .map(|(user, merged)| match user {
+                Ok(user) if merged => self.store_restored(user, RestorePolicy::Merge),
+                Ok(_) => Ok(MergeOutcome::Skipped),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    fn parse_merge_line(&self, line: &str) -> Result<User, DatabaseError> {
+        let mut user: User = serde_json::from_str(line)
+            .map_err(|e| DatabaseError::ValidationError(format!("Malformed record: {}", e)))?;
+        self.validate_id(&user.id)?;
+        self.validate_fields(&user.name, user.email.as_deref(), user.age, None)?;
+        user.email = user.email.map(|email| self.stored_email(&email));
+        user.sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        Ok(user)
+    }
+
+    /// Stores one restored row according to `policy`.
+    fn restore_row(
+        &self,
+        row: LoadedRow,
+        policy: RestorePolicy,
+    ) -> Result<MergeOutcome, DatabaseError> {
+        if let Some(id) = &row.id {
+            self.validate_id(id)?;
+        }
+        self.validate_fields(&row.name, row.email.as_deref(), row.age, None)?;
+        let now = self.clock.now();
+        let created_at = row.created_at.unwrap_or(now);
+        let user = User {
+            id: row.id.unwrap_or_else(|| self.new_id()),
+            name: row.name,
+            email: row.email.as_deref().map(|email| self.stored_email(email)),
+            age: row.age,
+            created_at,
+            updated_at: row.updated_at.unwrap_or(created_at),
+            email_history: Vec::new(),
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            name_updated_at: None,
+            email_updated_at: None,
+            age_updated_at: None,
+        };
+        self.store_restored(user, policy)
+    }
+
+    /// Stores a user that keeps its id, resolving a stored user with the same
+    /// id according to `policy`. `user` must already be validated.
+    fn store_restored(
+        &self,
+        user: User,
+        policy: RestorePolicy,
+    ) -> Result<MergeOutcome, DatabaseError> {
+        let tables = self.tables.load_full();
+        let Some(mut stored) = tables.db.get_mut(&user.id) else {
+            return self.insert_new_user(user).map(MergeOutcome::Created);
+        };
+        if policy == RestorePolicy::SkipExisting
+            || (policy == RestorePolicy::Merge && user.updated_at <= stored.updated_at)
+        {
+            return Ok(MergeOutcome::Skipped);
+        }
+
+        self.reindex_email(&user.id, stored.email.as_deref(), user.email.as_deref())?;
+        if user.name != stored.name {
+            self.unindex_name(&stored.name, &user.id);
+            self.index_name(&user.name, &user.id);
+        }
+        *stored = user.clone();
+        drop(stored);
+        self.invalidate(&user.id);
+        Ok(MergeOutcome::Replaced(user))
+    }
+
+    /// A fingerprint of the stored data, for checking that two instances
+    /// hold the same users. Covers the exported fields (id, name, email, age
+    /// and timestamps) and XORs per-user hashes, so map order does not
+    /// matter. Only comparable between builds of the same Rust version.
+    pub fn dataset_hash(&self) -> u64 {
+        let tables = self.tables.load_full();
+        on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .map(|kv| user_hash(kv.value()))
+                .reduce(|| 0, |a, b| a ^ b)
+        })
+    }
+
+    /// Number of users in each shard of the user map, in shard order. A
+    /// strongly uneven spread points at a hashing problem.
+    pub fn shard_sizes(&self) -> Vec<usize> {
+        let tables = self.tables.load_full();
+        tables
+            .db
+            .shards()
+            .iter()
+            .map(|shard| shard.read().len())
+            .collect()
+    }
+
+    /// Shrinks the user map and indexes to fit their contents, e.g. after
+    /// mass deletes. Each shard is locked while it is rebuilt. Returns an
+    /// estimate of the bytes released, counting only the table slots.
+    pub fn compact(&self) -> usize {
+        let tables = self.tables.load_full();
+        let user_slot = std::mem::size_of::<(String, User)>();
+        let index_slot = std::mem::size_of::<(String, String)>();
+        let names_slot = std::mem::size_of::<(String, Vec<String>)>();
+
+        let users_before = tables.db.capacity();
+        let emails_before = tables.email_index.capacity();
+        let names_before = tables.name_index.as_ref().map_or(0, DashMap::capacity);
+        tables.db.shrink_to_fit();
+        tables.email_index.shrink_to_fit();
+        if let Some(index) = &tables.name_index {
+            index.shrink_to_fit();
+        }
+        let names_after = tables.name_index.as_ref().map_or(0, DashMap::capacity);
+
+        users_before.saturating_sub(tables.db.capacity()) * user_slot
+            + emails_before.saturating_sub(tables.email_index.capacity()) * index_slot
+            + names_before.saturating_sub(names_after) * names_slot
+    }
+
+    /// Builds a fresh table and indexes from `users` on the rayon pool, then
+    /// swaps them in with one pointer store. Readers that already loaded the
+    /// old tables finish against them; later reads only see the new dataset.
+    /// Writes racing with the swap may land in the old tables and be lost, so
+    /// pause writers during a reload. Fails without swapping if two users
+    /// share an id or an email. Returns the number of users now stored. No
+    /// events are emitted for the swap.
+    pub fn replace_dataset(&self, users: Vec<User>) -> Result<usize, DatabaseError> {
+        let current = self.tables.load_full();
+        let tables = Tables::new(self.shard_amount, self.hasher, current.name_index.is_some());
+        let next_sequence = on_rayon(|| users.par_iter().map(|user| user.sequence + 1).max());
+        on_rayon(|| {
+            users.into_par_iter().try_for_each(|user| {
+                if let Some(email) = &user.email {
+                    match tables.email_index.entry(self.email_key(email)) {
+                        Entry::Occupied(_) => {
+                            return Err(DatabaseError::ValidationError(format!(
+                                "Email of user {} is already used in the dataset",
+                                user.id
+                            )));
+                        }
+                        Entry::Vacant(slot) => {
+                            slot.insert(user.id.clone());
+                        }
+                    }
+                }
+                if let Some(index) = &tables.name_index {
+                    index
+                        .entry(Self::name_key(&user.name))
+                        .or_default()
+                        .push(user.id.clone());
+                }
+                match tables.db.entry(user.id.clone()) {
+                    Entry::Occupied(_) => Err(DatabaseError::ValidationError(format!(
+                        "User id {} appears twice in the dataset",
+                        user.id
+                    ))),
+                    Entry::Vacant(slot) => {
+                        slot.insert(user);
+                        Ok(())
+                    }
+                }
+            })
+        })?;
+        if let Some(next) = next_sequence {
+            self.sequence.fetch_max(next, Ordering::Relaxed);
+        }
+
+        let old = self.tables.swap(Arc::new(tables));
+        let tables = self.tables.load();
+        self.user_counters
+            .retain(|id, _| tables.db.contains_key(id));
+        self.publish_count();
+        if self.on_invalidate.is_some() {
+            for kv in old.db.iter() {
+                self.invalidate(kv.key());
+            }
+        }
+        Ok(tables.db.len())
+    }
+
+    /// Makes room for `additional` more users in the user map and indexes,
+    /// so a bulk load of known size does not resize them as it goes. The
+    /// load methods call this with the row count of the file.
+    pub fn reserve(&self, additional: usize) {
+        let tables = self.tables.load_full();
+        reserve_map(&tables.db, additional);
+        reserve_map(&tables.email_index, additional);
+        if let Some(index) = &tables.name_index {
+            reserve_map(index, additional);
+        }
+    }
+
+    /// Removes every user and index entry.
+    fn clear(&self) {
+        let tables = self.tables.load_full();
+        let ids: Vec<String> = match self.on_invalidate {
+            Some(_) => tables.db.iter().map(|kv| kv.key().clone()).collect(),
+            None => Vec::new(),
+        };
+        tables.db.clear();
+        tables.email_index.clear();
+        self.user_counters.clear();
+        if let Some(index) = &tables.name_index {
+            index.clear();
+        }
+        self.publish_count();
+        for id in &ids {
+            self.invalidate(id);
+        }
+    }
+
+    /// Groups loaded rows by normalized email and keeps one row per email
+    /// according to `policy` (`Reject` keeps the first; the caller bails out).
+    fn resolve_duplicate_emails(
+        &self,
+        rows: Vec<LoadedRow>,
+        policy: DuplicateEmailPolicy,
+    ) -> (Vec<CreateUserRequest>, Vec<DuplicateEmail>) {
+        let mut by_email: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut order = Vec::new();
+        let mut keep = vec![false; rows.len()];
+        for (row, loaded) in rows.iter().enumerate() {
+            // Rows without an email cannot collide.
+            let Some(email) = loaded.email.as_deref() else {
+                keep[row] = true;
+                continue;
+            };
+            let email = self.email_normalizer.normalize(email);
+            by_email
+                .entry(email.clone())
+                .or_insert_with(|| {
+                    order.push(email);
+                    Vec::new()
+                })
+                .push(row);
+        }
+
+        let mut duplicates = Vec::new();
+        for email in order {
+            let occurrences = &by_email[&email];
+            let kept = match policy {
+                DuplicateEmailPolicy::KeepLast => occurrences[occurrences.len() - 1],
+                _ => occurrences[0],
+            };
+            keep[kept] = true;
+            if occurrences.len() > 1 {
+                duplicates.push(DuplicateEmail {
+                    occurrences: occurrences
+                        .iter()
+                        .map(|&row| (row, rows[row].id.clone()))
+                        .collect(),
+                    email,
+                });
+            }
+        }
+
+        let requests = rows
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|(row, _)| row.into_request())
+            .collect();
+        (requests, duplicates)
+    }
+
+    pub async fn complex_user_operation(&self, id: &str) -> Result<User, DatabaseError> {
+        const MAX_RETRIES: u32 = 3;
+        const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+        for attempt in 1..=MAX_RETRIES {
+            match self.get_user(id).await {
+                Ok(mut user) => {
+                    sleep(Duration::from_millis(50)).await;
+                    user.name = format!("Processed: {}", user.name);
+                    let update_req = UpdateUserRequest {
+                        name: Some(user.name.clone()),
+                        email: None,
+                        age: None,
+                    };
+                    return self.update_user(id, update_req).await;
+                }
+                Err(DatabaseError::UserNotFound) => return Err(DatabaseError::UserNotFound),
+                Err(_) if attempt < MAX_RETRIES => {
+                    self.reporter.report(ProgressEvent::Retry { attempt });
+                    sleep(RETRY_DELAY).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DatabaseError::UserNotFound)
+    }
+
+    /// Runs the synchronous validation rules over `reqs` in parallel, without
+    /// the simulated delay and without touching the store. Results are in
+    /// input order.
+    pub fn validate_batch(&self, reqs: &[CreateUserRequest]) -> Vec<Result<(), DatabaseError>> {
+        let validate = |req: &CreateUserRequest| {
+            self.validate_fields(
+                &req.name,
+                req.email.as_deref(),
+                req.age,
+                req.country.as_deref(),
+            )
+        };
+        on_rayon(|| reqs.par_iter().map(validate).collect())
+    }
+
+    /// A fresh random id carrying the configured `id_prefix`.
+    fn new_id(&self) -> String {
+        format!("{}{}", self.id_prefix, Uuid::new_v4())
+    }
+
+    fn validate_id(&self, id: &str) -> Result<(), DatabaseError> {
+        if id.is_empty() {
+            return Err(DatabaseError::ValidationError(
+                "Id cannot be empty".to_string(),
+            ));
+        }
+        match &self.id_format {
+            IdFormat::Any => Ok(()),
+            IdFormat::Uuid { version } => {
+                match Uuid::parse_str(id.strip_prefix(self.id_prefix.as_str()).unwrap_or(id)) {
+                    Ok(uuid) if version.is_none_or(|v| uuid.get_version_num() == v) => Ok(()),
+                    Ok(uuid) => Err(DatabaseError::ValidationError(format!(
+                        "Id {} is a version {} UUID, expected version {}",
+                        id,
+                        uuid.get_version_num(),
+                        version.unwrap_or_default()
+                    ))),
+                    Err(e) => Err(DatabaseError::ValidationError(format!(
+                        "Id {} is not a UUID: {}",
+                        id, e
+                    ))),
+                }
+            }
+            IdFormat::Pattern(pattern) if pattern.is_match(id) => Ok(()),
+            IdFormat::Pattern(pattern) => Err(DatabaseError::ValidationError(format!(
+                "Id {} does not match {}",
+                id,
+                pattern.as_str()
+            ))),
+        }
+    }
+
+    fn validate_fields(
+        &self,
+        name: &str,
+        email: Option<&str>,
+        age: Option<u8>,
+        country: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        let violations = self.field_violations(name, email, age, country, self.validation_mode);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(DatabaseError::ValidationError(violations.join("; ")))
+        }
+    }
+
+    fn field_violations(
+        &self,
+        name: &str,
+        email: Option<&str>,
+        age: Option<u8>,
+        country: Option<&str>,
+        mode: ValidationMode,
+    ) -> Vec<String> {
+        let name_check = || name.is_empty().then(|| "Name cannot be empty".to_string());
+        let email_check = || match email {
+            Some(email) => self.email_violation(email),
+            None if self.email_required => Some("Email is required".to_string()),
+            None => None,
+        };
+        let age_check = || {
+            let age = age?;
+            match self.min_age_for(country) {
+                (min_age, Some(country)) if age < min_age => {
+                    Some(format!("Users in {} must be at least {}", country, min_age))
+                }
+                (min_age, _) => (!(min_age..=MAX_AGE).contains(&age))
+                    .then(|| format!("Age must be between {} and {}", min_age, MAX_AGE)),
+            }
+        };
+        let checks: [&dyn Fn() -> Option<String>; 3] = [&name_check, &email_check, &age_check];
+
+        match mode {
+            ValidationMode::FailFast => checks
+                .iter()
+                .find_map(|check| check())
+                .into_iter()
+                .collect(),
+            ValidationMode::CollectAll => checks.iter().filter_map(|check| check()).collect(),
+        }
+    }
+
+    /// Stored users that the current rules would reject, with every rule
+    /// each one breaks, sorted by id. Useful after tightening validation to
+    /// see what a cleanup has to fix.
+    pub fn find_invalid(&self) -> Vec<(String, Vec<String>)> {
+        let tables = self.tables.load_full();
+        let mut invalid: Vec<(String, Vec<String>)> = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .filter_map(|kv| {
+                    let user = kv.value();
+                    let mut violations = match self.validate_id(&user.id) {
+                        Err(DatabaseError::ValidationError(msg)) => vec![msg],
+                        _ => Vec::new(),
+                    };
+                    violations.extend(self.field_violations(
+                        &user.name,
+                        user.email.as_deref(),
+                        user.age,
+                        None,
+                        ValidationMode::CollectAll,
+                    ));
+                    (!violations.is_empty()).then(|| (user.id.clone(), violations))
+                })
+                .collect()
+        });
+        invalid.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        invalid
+    }
+
+    /// The minimum age for signups from `country`, and the country when it
+    /// has its own rule; other countries fall back to `min_age`.
+    fn min_age_for<'a>(&self, country: Option<&'a str>) -> (u8, Option<&'a str>) {
+        country
+            .and_then(|country| {
+                self.country_min_ages
+                    .get(&country.trim().to_uppercase())
+                    .map(|&min_age| (min_age, Some(country)))
+            })
+            .unwrap_or((self.min_age, None))
+    }
+
+    /// The first problem with `email`, if any. Later email rules assume the
+    /// earlier ones passed, so only one is ever reported.
+    fn email_violation(&self, email: &str) -> Option<String> {
+        let email = email.trim();
+        if email.is_empty() {
+            return Some("Email cannot be empty".to_string());
+        }
+        if email.len() > self.email_limits.max_len {
+            return Some(format!(
+                "Email is {} bytes, longer than the limit of {}",
+                email.len(),
+                self.email_limits.max_len
+            ));
+        }
+        if email.chars().any(char::is_whitespace) {
+            return Some("Email cannot contain spaces".to_string());
+        }
+        match email.split_once('@') {
+            Some((local, _)) if local.len() > self.email_limits.max_local_len => Some(format!(
+                "Email local part is {} bytes, longer than the limit of {}",
+                local.len(),
+                self.email_limits.max_local_len
+            )),
+            Some((_, domain)) if domain.len() > self.email_limits.max_domain_len => Some(format!(
+                "Email domain is {} bytes, longer than the limit of {}",
+                domain.len(),
+                self.email_limits.max_domain_len
+            )),
+            Some((local, domain)) if !local.is_empty() && !domain.is_empty() => None,
+            _ => Some("Invalid email format".to_string()),
+        }
+    }
+
+    /// Cross-checks the email index against the map and the stats counters
+    /// against each other, and re-validates every stored user.
+    pub async fn self_check(&self) -> HealthStatus {
+        let tables = self.tables.load_full();
+        let mut issues = Vec::new();
+
+        let users = on_rayon(|| tables.db.par_iter().filter(|kv| kv.email.is_some()).count());
+        let indexed = tables.email_index.len();
+        if users != indexed {
+            issues.push(format!(
+                "email index has {} entries but {} users with an email are stored",
+                indexed, users
+            ));
+        }
+
+        // Walk the map and probe the index, never the other way round: holding
+        // an index guard while reading `db` could deadlock with `update_user`.
+        let unindexed = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .filter(|kv| {
+                    kv.value().email.as_deref().is_some_and(|email| {
+                        tables
+                            .email_index
+                            .get(&self.email_key(email))
+                            .is_none_or(|id| id.value() != kv.key())
+                    })
+                })
+                .count()
+        });
+        if unindexed > 0 {
+            issues.push(format!(
+                "{} users are missing or mismatched in the email index",
+                unindexed
+            ));
+        }
+
+        let invalid = on_rayon(|| {
+            tables
+                .db
+                .par_iter()
+                .filter(|kv| {
+                    let user = kv.value();
+                    self.validate_fields(&user.name, user.email.as_deref(), user.age, None)
+                        .is_err()
+                })
+                .count()
+        });
+        if invalid > 0 {
+            issues.push(format!("{} stored users fail validation", invalid));
+        }
+
+        let stats = self.get_stats().await;
+        let counted = stats.create_count
+            + stats.read_count
+            + stats.update_count
+            + stats.delete_count
+            + stats.parallel_operations
+            + stats.create_failed
+            + stats.update_failed
+            + stats.delete_failed;
+        if stats.total_operations < counted {
+            issues.push(format!(
+                "total_operations ({}) is below the sum of per-op counters ({})",
+                stats.total_operations, counted
+            ));
+        }
+        if stats.validation_failed > stats.create_failed {
+            issues.push(format!(
+                "validation_failed ({}) exceeds create_failed ({})",
+                stats.validation_failed, stats.create_failed
+            ));
+        }
+
+        if issues.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded(issues)
+        }
+    }
+
+    fn record_email(&self, email: Option<&str>) {
+        let Some(email) = email else {
+            return;
+        };
+        let span = tracing::Span::current();
+        if self.redact_pii {
+            span.record("email", "[redacted]");
+        } else {
+            span.record("email", email);
+        }
+    }
+
+    async fn record_parallel_op(
+        &self,
+        kind: ParallelOpKind,
+        started_at: chrono::DateTime<chrono::Utc>,
+        duration: Duration,
+        item_count: usize,
+    ) {
+        self.increment_stat(|stats| stats.parallel_operations += 1)
+            .await;
+        let mut history = self.parallel_history.lock().unwrap();
+        if history.len() == PARALLEL_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(ParallelOpRecord {
+            kind,
+            started_at,
+            duration,
+            item_count,
+        });
+    }
+
+    /// Timing and size of the most recent bulk runs, oldest first.
+    pub fn parallel_history(&self) -> Vec<ParallelOpRecord> {
+        self.parallel_history
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn publish_count(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let tables = self.tables.load_full();
+        self.count_tx.send_replace(tables.db.len());
+    }
+
+    /// Observes the live user count; the receiver always holds the latest
+    /// value and is notified on every insert and delete.
+    pub fn watch_count(&self) -> watch::Receiver<usize> {
+        self.count_tx.subscribe()
+    }
+
+    fn record_op(&self, kind: OpKind, id: Option<&str>, success: bool) {
+        self.op_log.push(OpRecord {
+            kind,
+            id: id.map(str::to_string),
+            timestamp: self.clock.now(),
+            success,
+        });
+    }
+
+    /// The last `n` recorded CRUD operations, newest first.
+    pub fn recent_operations(&self, n: usize) -> Vec<OpRecord> {
+        self.op_log.recent(n)
+    }
+
+    async fn increment_stat<F>(&self, updater: F)
+    where
+        F: FnOnce(&mut ServiceStats),
+    {
+        self.apply_stat(updater);
+    }
+
+    fn apply_stat<F>(&self, updater: F)
+    where
+        F: FnOnce(&mut ServiceStats),
+    {
+        let mut stats = self.stats.entry(()).or_default();
+        stats.total_operations += 1;
+        updater(&mut stats);
+    }
+
+    pub async fn get_stats(&self) -> ServiceStats {
+        self.stats
+            .get(&())
+            .map(|s| s.value().clone())
+            .unwrap_or_default()
+    }
+
+    /// What changed since `previous`, an earlier `get_stats` snapshot.
+    pub fn stats_delta(&self, previous: &ServiceStats) -> ServiceStats {
+        self.stats
+            .get(&())
+            .map(|s| s.value().saturating_sub(previous))
+            .unwrap_or_default()
+    }
+
+    /// Every committed mutation from now on, with the same coverage as
+    /// `UserServiceBuilder::event_sink`. A receiver that falls more than
+    /// `EVENT_BROADCAST_CAPACITY` events behind loses the oldest ones and is
+    /// told how many with `RecvError::Lagged`.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<UserEvent> {
+        self.events.subscribe()
+    }
+
+    /// Appends every event from now on to `path` as an NDJSON line with the
+    /// time it was logged. Writes are buffered and flushed every
+    /// `EVENT_LOG_FLUSH_INTERVAL`. Events dropped because the writer lagged
+    /// are counted and reported on stderr. The task ends, after a final
+    /// flush, once the service is dropped.
+    pub fn spawn_event_log_writer(
+        self: Arc<Self>,
+        path: impl Into<std::path::PathBuf>,
+    ) -> tokio::task::JoinHandle<()> {
+        #[derive(Serialize)]
+        struct EventLogLine<'a> {
+            timestamp: chrono::DateTime<chrono::Utc>,
+            #[serde(flatten)]
+            event: &'a UserEvent,
+        }
+
+        let path = path.into();
+        let mut events = self.subscribe_events();
+        let clock = Arc::clone(&self.clock);
+        drop(self);
+        tokio::spawn(async move {
+            let file = match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("❌ Event log could not open {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            let mut writer = BufWriter::new(file);
+            let mut flush_ticker = tokio::time::interval(EVENT_LOG_FLUSH_INTERVAL);
+            let mut dropped: u64 = 0;
+            loop {
+                let event = tokio::select! {
+                    received = events.recv() => received,
+                    _ = flush_ticker.tick() => {
+                        if let Err(e) = writer.flush().await {
+                            eprintln!("❌ Event log failed to write {}: {}", path.display(), e);
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        dropped += missed;
+                        eprintln!(
+                            "⚠️ Event log lagged: dropped {} events ({} in total)",
+                            missed, dropped
+                        );
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let line = EventLogLine {
+                    timestamp: clock.now(),
+                    event: &event,
+                };
+                let mut line = match serde_json::to_vec(&line) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        eprintln!("❌ Event log failed to encode event: {}", e);
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                if let Err(e) = writer.write_all(&line).await {
+                    eprintln!("❌ Event log failed to write {}: {}", path.display(), e);
+                    return;
+                }
+            }
+            if let Err(e) = writer.flush().await {
+                eprintln!("❌ Event log failed to write {}: {}", path.display(), e);
+            }
+        })
+    }
+
+    /// Takes a stats sample now and keeps it in the in-memory history, which
+    /// holds the last `STATS_HISTORY_CAPACITY` samples. `spawn_stats_logger`
+    /// records one per tick; call this to sample without it.
+    pub async fn sample_stats(&self) {
+        self.record_stats_sample().await;
+    }
+
+    async fn record_stats_sample(&self) -> StatsSample {
+        let sample = StatsSample {
+            timestamp: self.clock.now(),
+            stats: self.get_stats().await,
+        };
+        let mut history = self.stats_history.lock().unwrap();
+        if history.len() == STATS_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(sample.clone());
+        sample
+    }
+
+    /// Writes the in-memory stats history as CSV, oldest sample first: a
+    /// `timestamp` column followed by one column per counter. Returns the
+    /// number of samples written.
+    pub async fn export_stats_history_csv(
+        &self,
+        path: &str,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let samples: Vec<StatsSample> =
+            self.stats_history.lock().unwrap().iter().cloned().collect();
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for (i, sample) in samples.iter().enumerate() {
+            let serde_json::Value::Object(counters) = serde_json::to_value(&sample.stats)? else {
+                return Err("stats did not serialize to an object".into());
+            };
+            if i == 0 {
+                wtr.write_record(
+                    std::iter::once("timestamp").chain(counters.keys().map(String::as_str)),
+                )?;
+            }
+            let mut record = vec![sample.timestamp.to_rfc3339()];
+            record.extend(counters.values().map(|value| value.to_string()));
+            wtr.write_record(&record)?;
+        }
+        let data = wtr.into_inner().map_err(|e| e.into_error())?;
+        self.write_with_retry(path, &data).await?;
+        Ok(samples.len())
+    }
+
+    /// Appends one timestamped JSON line of the current stats to `path` on
+    /// every tick. The stats are cloned before any I/O so the write never
+    /// holds the stats entry. The task only holds a weak reference, so it
+    /// ends at the first tick after the service is dropped; it also stops on
+    /// the first write error.
+    pub fn spawn_stats_logger(
+        self: Arc<Self>,
+        path: impl Into<std::path::PathBuf>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let path = path.into();
+        let service = Arc::downgrade(&self);
+        drop(self);
+        tokio::spawn(async move {
+            let file = match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("❌ Stats logger could not open {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            let mut writer = BufWriter::new(file);
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(service) = service.upgrade() else {
+                    return;
+                };
+                let sample = service.record_stats_sample().await;
+                drop(service);
+                let mut line = match serde_json::to_vec(&sample) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        eprintln!("❌ Stats logger failed to encode sample: {}", e);
+                        return;
+                    }
+                };
+                line.push(b'\n');
+                if let Err(e) = async {
+                    writer.write_all(&line).await?;
+                    writer.flush().await
+                }
+                .await
+                {
+                    eprintln!("❌ Stats logger failed to write {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct StatsSample {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    stats: ServiceStats,
+}
+
+/// An empty map using `hasher`, with `shard_amount` shards or DashMap's
+/// default count.
+fn new_map<K, V>(shard_amount: Option<usize>, hasher: MapHasher) -> DashMap<K, V, TableHasher>
+where
+    K: Eq + std::hash::Hash,
+{
+    let hasher = TableHasher::new(hasher);
+    match shard_amount {
+        Some(shards) => DashMap::with_hasher_and_shard_amount(hasher, shards),
+        None => DashMap::with_hasher(hasher),
+    }
+}
+
+/// The keys in shard `shard` of `map`, copied under the shard's read lock.
+fn shard_keys<K, V>(map: &DashMap<K, V, TableHasher>, shard: usize) -> Vec<K>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    let shard = map.shards()[shard].read();
+    // SAFETY: the buckets are only read while the read guard keeps the table
+    // alive and unchanged, and none outlives this call.
+    unsafe {
+        shard
+            .iter()
+            .map(|bucket| bucket.as_ref().0.clone())
+            .collect()
+    }
+}
+
+/// Reserves room for `additional` more entries spread evenly over the
+/// shards, locking one shard at a time.
+fn reserve_map<K, V>(map: &DashMap<K, V, TableHasher>, additional: usize)
+where
+    K: Eq + std::hash::Hash,
+{
+    let per_shard = additional.div_ceil(map.shards().len());
+    for shard in map.shards() {
+        shard
+            .write()
+            .reserve(per_shard, |(key, _)| map.hash_usize(key) as u64);
+    }
+}
+
+/// Whether the global rayon pool started. When it cannot spawn its threads,
+/// rayon panics on every parallel call made outside another pool. Warns once,
+/// the first time the pool is found missing.
+fn rayon_usable() -> bool {
+    static GLOBAL_POOL_STARTED: OnceLock<bool> = OnceLock::new();
+    *GLOBAL_POOL_STARTED.get_or_init(|| {
+        let started = std::panic::catch_unwind(rayon::current_num_threads).is_ok();
+        if !started {
+            tracing::warn!("rayon pool is unavailable, running parallel work sequentially");
+        }
+        started
+    })
+}
+
+/// Runs `op`, whose parallel iterators then use the global rayon pool, or a
+/// one-thread pool made of the calling thread when the global pool failed to
+/// start. Parallel work thus degrades to sequential instead of panicking;
+/// every rayon call in the service goes through here.
+fn on_rayon<R: Send>(op: impl FnOnce() -> R + Send) -> R {
+    thread_local! {
+        static FALLBACK_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .use_current_thread()
+            .build()
+            .expect("a pool on the current thread spawns no threads");
+    }
+    if rayon_usable() {
+        op()
+    } else {
+        FALLBACK_POOL.with(|pool| pool.install(op))
+    }
+}
+
+/// Maps `items` on the rayon pool one `BULK_BATCH_SIZE` chunk at a time.
+/// Each chunk runs from a blocking task, so no tokio worker thread is tied
+/// up by the transform and the runtime keeps polling other futures.
+async fn par_map_chunked<T, U, F>(items: Vec<T>, f: F) -> Result<Vec<U>, DatabaseError>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> U + Sync + Send + 'static,
+{
+    let f = Arc::new(f);
+    let mut out = Vec::with_capacity(items.len());
+    let mut items = items.into_iter();
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(BULK_BATCH_SIZE).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let f = Arc::clone(&f);
+        let mapped = tokio::task::spawn_blocking(move || {
+            on_rayon(|| {
+                chunk
+                    .into_par_iter()
+                    .map(|item| f(item))
+                    .collect::<Vec<U>>()
+            })
+        })
+        .await?;
+        out.extend(mapped);
+    }
+    Ok(out)
+}
+
+async fn join_tasks<T, I>(tasks: I) -> Vec<Result<T, DatabaseError>>
+where
+    I: IntoIterator<Item = tokio::task::JoinHandle<Result<T, DatabaseError>>>,
+{
+    futures::future::join_all(tasks)
+        .await
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|e| Err(e.into())))
+        .collect()
+}
+
+fn shard_for(id: &str, shards: usize) -> usize {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+fn user_hash(user: &User) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    user.id.hash(&mut hasher);
+    user.name.hash(&mut hasher);
+    user.email.hash(&mut hasher);
+    user.age.hash(&mut hasher);
+    user.created_at.hash(&mut hasher);
+    user.updated_at.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Substring match on name or email; `query` must already be normalized by
+/// `options`.
+fn matches_query(user: &User, query: &str, options: SearchOptions) -> bool {
+    options.normalize(&user.name).contains(query)
+        || user
+            .email
+            .as_deref()
+            .is_some_and(|email| options.normalize(email).contains(query))
+}
+
+fn uppercase_name(req: CreateUserRequest) -> CreateUserRequest {
+    CreateUserRequest {
+        name: req.name.to_uppercase(),
+        ..req
+    }
+}
+
+impl Default for UserService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How many users the bulk section of `run_demo` inserts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DemoScale {
+    /// 1,000 users: finishes in seconds on any machine.
+    #[default]
+    Small,
+    /// 100,000 users.
+    Medium,
+    /// 10,000,000 users: needs several GB of RAM.
+    Large,
+}
+
+impl DemoScale {
+    pub fn user_count(self) -> usize {
+        match self {
+            DemoScale::Small => 1_000,
+            DemoScale::Medium => 100_000,
+            DemoScale::Large => 10_000_000,
+        }
+    }
+}
+
+async fn read_csv_records(
+    path: &str,
+) -> Result<HashMap<String, UserCsvRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let contents = tokio::fs::read(path).await?;
+    let mut rdr = CsvOptions::default()
+        .reader_builder()
+        .from_reader(contents.as_slice());
+    let mut records = HashMap::new();
+    for result in rdr.deserialize() {
+        let record: UserCsvRecord =
+            result.map_err(|e| format!("CSV deserialize error in {}: {}", path, e))?;
+        records.insert(record.id.clone(), record);
+    }
+    Ok(records)
+}
+
+/// Snapshot files written by `save_snapshot` in `dir`, oldest first.
+pub async fn list_snapshots(
+    dir: &str,
+) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut snapshots = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("users-") && name.ends_with(".snap") {
+            snapshots.push(entry.path());
+        }
+    }
+    // The timestamp format sorts lexicographically in time order.
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Compares two CSV exports by id, e.g. before and after a migration.
+pub async fn diff_snapshots(
+    a: &str,
+    b: &str,
+) -> Result<SnapshotDiff, Box<dyn std::error::Error + Send + Sync>> {
+    let (before, after) = tokio::try_join!(read_csv_records(a), read_csv_records(b))?;
+
+    let mut diff = SnapshotDiff {
+        added: after
+            .keys()
+            .filter(|id| !before.contains_key(*id))
+            .cloned()
+            .collect(),
+        ..SnapshotDiff::default()
+    };
+    for (id, old) in &before {
+        match after.get(id) {
+            None => diff.removed.push(id.clone()),
+            Some(new) => {
+                let changed = old.changed_fields(new);
+                if !changed.is_empty() {
+                    diff.changed.push((id.clone(), changed));
+                }
+            }
+        }
+    }
+    diff.added.sort_unstable();
+    diff.removed.sort_unstable();
+    diff.changed.sort_unstable();
+    Ok(diff)
+}
+
+/// How `run_demo` prints its progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Emoji prose for people.
+    #[default]
+    Pretty,
+    /// One JSON object per line with `operation`, `count` and `duration_ms`
+    /// keys, for CI to parse.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads `--log-format pretty|json` (or `--log-format=json`) from the
+    /// command-line arguments, without the program name.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut format = LogFormat::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let value = match arg.strip_prefix("--log-format") {
+                Some("") => args.next(),
+                Some(rest) => rest.strip_prefix('=').map(str::to_string),
+                None => return Err(format!("Unknown argument: {}", arg)),
+            };
+            format = match value.as_deref() {
+                Some("pretty") => LogFormat::Pretty,
+                Some("json") => LogFormat::Json,
+                Some(other) => return Err(format!("Unknown log format: {}", other)),
+                None => return Err("--log-format needs a value".to_string()),
+            };
+        }
+        Ok(format)
+    }
+
+    /// The reporter that prints service events in this format.
+    pub fn reporter(self) -> Arc<dyn Reporter> {
+        match self {
+            LogFormat::Pretty => Arc::new(ConsoleReporter),
+            LogFormat::Json => Arc::new(JsonReporter),
+        }
+    }
+
+    fn section(self, title: &str) {
+        if self == LogFormat::Pretty {
+            println!("\n=== {} ===", title);
+        }
+    }
+
+    /// One demo step: `pretty` is only rendered in the pretty format.
+    fn step(
+        self,
+        operation: &str,
+        count: usize,
+        duration: Duration,
+        pretty: impl FnOnce() -> String,
+    ) {
+        match self {
+            LogFormat::Pretty => println!("{}", pretty()),
+            LogFormat::Json => print_json_line(
+                operation,
+                Some(count),
+                Some(duration),
+                serde_json::Map::new(),
+            ),
+        }
+    }
+}
+
+pub async fn run_demo(service: Arc<UserService>, scale: DemoScale, log: LogFormat) {
+    let workers = tokio::runtime::Handle::current().metrics().num_workers();
+    match log {
+        LogFormat::Pretty => {
+            println!("🚀 Starting Advanced Rust CRUD Demo with Parallel Processing\n");
+            println!("🔧 Tokio workers: {}", workers);
+            println!("🔧 Rayon threads: {}", rayon::current_num_threads());
+        }
+        LogFormat::Json => {
+            let mut fields = serde_json::Map::new();
+            fields.insert("tokio_workers".to_string(), workers.into());
+            fields.insert(
+                "rayon_threads".to_string(),
+                rayon::current_num_threads().into(),
+            );
+            print_json_line("demo_started", None, None, fields);
+        }
+    }
+
+    log.section("🔧 Basic CRUD");
+    let start = Instant::now();
+    let create_req = CreateUserRequest {
+        name: "John Doe".to_string(),
+        email: Some("john@example.com".to_string()),
+        age: Some(30),
+        idempotency_key: None,
+        country: None,
+    };
+
+    match service.create_user(create_req).await {
+        Ok(user) => {
+            log.step("create", 1, start.elapsed(), || {
+                format!("✅ Created: {} [Time: {:?}]", user.name, start.elapsed())
+            });
+            let start = Instant::now();
+            match service.get_user(&user.id).await {
+                Ok(found) => log.step("get", 1, start.elapsed(), || {
+                    format!("✅ Found: {} [Time: {:?}]", found.name, start.elapsed())
+                }),
+                Err(e) => log.step("get", 0, start.elapsed(), || {
+                    format!("❌ Get failed: {} [Time: {:?}]", e, start.elapsed())
+                }),
+            }
+            let start = Instant::now();
+            let update = UpdateUserRequest {
+                name: Some("John Smith".to_string()),
+                email: None,
+                age: Some(31),
+            };
+            match service.update_user(&user.id, update).await {
+                Ok(updated) => log.step("update", 1, start.elapsed(), || {
+                    format!("✅ Updated: {} [Time: {:?}]", updated.name, start.elapsed())
+                }),
+                Err(e) => log.step("update", 0, start.elapsed(), || {
+                    format!("❌ Update failed: {} [Time: {:?}]", e, start.elapsed())
+                }),
+            }
+        }
+        Err(e) => log.step("create", 0, start.elapsed(), || {
+            format!("❌ Create failed: {} [Time: {:?}]", e, start.elapsed())
+        }),
+    }
+
+    log.section(&format!(
+        "🔥 BULK: {} USERS (Rayon(CPU Abound) + Tokio(IO Abound) + DashMap)",
+        scale.user_count()
+    ));
+    let bulk_req = (0..scale.user_count()).map(|i| CreateUserRequest {
+        name: format!("BulkUser{}", i),
+        email: Some(format!("user{}@bulk.com", i)),
+        age: Some(20 + (i % 80) as u8),
+        idempotency_key: None,
+        country: None,
+    });
+
+    let start = Instant::now();
+    let summary = service.clone().bulk_create_from_iter(bulk_req).await;
+    log.step("bulk_create", summary.created, start.elapsed(), || {
+        format!(
+            "✅ Bulk done in {:?} | {} success",
+            start.elapsed(),
+            summary.created
+        )
+    });
+
+    log.section("⚡ FAST Concurrent Tasks (5)");
+    let start = Instant::now();
+    let _ = service.clone().fast_concurrent_operations().await;
+    log.step("fast_concurrent_operations", 5, start.elapsed(), || {
+        format!("✅ Fast concurrent ops done in {:?}", start.elapsed())
+    });
+
+    log.section("🚀 BULK Concurrent Insert (5000)");
+    let start = Instant::now();
+    let _ = service.clone().bulk_insert_concurrent(5000).await;
+    log.step("bulk_insert_concurrent", 5000, start.elapsed(), || {
+        format!("✅ Bulk concurrent insert done in {:?}", start.elapsed())
+    });
+
+    log.section("📊 Final Stats");
+    let stats = service.get_stats().await;
+    let health = service.self_check().await;
+    match log {
+        LogFormat::Pretty => {
+            println!("{:#}", stats);
+            match health {
+                HealthStatus::Healthy => println!("Health: ✅ healthy"),
+                HealthStatus::Degraded(issues) => {
+                    println!("Health: ⚠️ degraded: {}", issues.join("; "))
+                }
+            }
+        }
+        LogFormat::Json => {
+            let mut fields = serde_json::Map::new();
+            fields.insert(
+                "stats".to_string(),
+                serde_json::to_value(&stats).unwrap_or_default(),
+            );
+            fields.insert(
+                "healthy".to_string(),
+                matches!(health, HealthStatus::Healthy).into(),
+            );
+            print_json_line("stats", None, None, fields);
+        }
+    }
+
+    log.section("💾 SAVE TO CSV");
+    let csv_path = "users_export.csv";
+    if let Err(e) = service.bulk_save_to_csv(csv_path).await {
+        eprintln!("❌ Save to CSV failed: {}", e);
+    }
+
+    log.section("📥 LOAD FROM CSV");
+    if let Err(e) = service.clone().bulk_load_from_csv(csv_path).await {
+        eprintln!("❌ Load from CSV failed: {}", e);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let log = LogFormat::from_args(std::env::args().skip(1))?;
+    let service = Arc::new(UserService::builder().reporter(log.reporter()).build());
+    run_demo(service, DemoScale::Small, log).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    fn quiet() -> UserServiceBuilder {
+        UserService::builder().reporter(Arc::new(SilentReporter))
+    }
+
+    fn service() -> Arc<UserService> {
+        Arc::new(quiet().build())
+    }
+
+    fn req(name: &str, email: &str, age: u8) -> CreateUserRequest {
+        CreateUserRequest {
+            name: name.to_string(),
+            email: Some(email.to_string()),
+            age: Some(age),
+            idempotency_key: None,
+            country: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn with_user_extracts_a_field_without_cloning() {
+        let svc = service();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(svc.with_user(&user.id, |u| u.age).await.unwrap(), Some(30));
+        assert!(matches!(
+            svc.with_user("missing", |u| u.age).await,
+            Err(DatabaseError::UserNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn bulk_create_from_iter_drains_a_lazy_generator() {
+        let svc = service();
+        let pulled = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&pulled);
+        let requests = (0..12).map(move |i| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            req(
+                &format!("Lazy {}", i),
+                &format!("lazy{}@example.com", i),
+                30,
+            )
+        });
+        let summary = Arc::clone(&svc).bulk_create_from_iter(requests).await;
+        assert_eq!((summary.created, summary.failed), (12, 0));
+        assert_eq!(pulled.load(Ordering::Relaxed), 12);
+        assert_eq!(svc.list_users().await.unwrap().len(), 12);
+    }
+
+    #[tokio::test]
+    async fn failed_create_increments_the_failure_counter() {
+        let svc = service();
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert!(
+            svc.create_user(req("Ann", "ann@example.com", 30))
+                .await
+                .is_err()
+        );
+        assert!(
+            svc.create_user(req("", "bob@example.com", 30))
+                .await
+                .is_err()
+        );
+        let stats = svc.get_stats().await;
+        assert_eq!(stats.create_count, 1);
+        assert_eq!(stats.create_failed, 2);
+        assert_eq!(stats.validation_failed, 1);
+    }
+
+    fn at(rfc3339: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&chrono::Utc)
+    }
+
+    #[tokio::test]
+    async fn update_moves_updated_at_on_the_mock_clock() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock.clone()).build();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(user.created_at, at("2024-01-01T00:00:00Z"));
+        assert_eq!(user.updated_at, user.created_at);
+
+        clock.advance(chrono::Duration::minutes(5));
+        let update = UpdateUserRequest {
+            name: Some("Anna".to_string()),
+            email: None,
+            age: None,
+        };
+        let updated = svc.update_user(&user.id, update).await.unwrap();
+        assert_eq!(updated.created_at, at("2024-01-01T00:00:00Z"));
+        assert_eq!(updated.updated_at, at("2024-01-01T00:05:00Z"));
+    }
+
+    #[tokio::test]
+    async fn oversized_bulk_is_rejected_without_inserting() {
+        let svc = Arc::new(quiet().max_bulk_size(3).build());
+        let requests: Vec<_> = (0..4)
+            .map(|i| req("Big", &format!("big{}@example.com", i), 30))
+            .collect();
+        let result = Arc::clone(&svc).bulk_create_users(requests).await;
+        assert!(matches!(
+            result,
+            Err(DatabaseError::InputTooLarge { size: 4, max: 3 })
+        ));
+        assert!(svc.list_users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn columnar_snapshot_aggregates_match_the_table() {
+        let svc = service();
+        svc.create_user(req("Ann", "ann@a.com", 20)).await.unwrap();
+        svc.create_user(req("Bob", "bob@b.com", 40)).await.unwrap();
+        svc.create_user(req("Cy", "cy@b.com", 60)).await.unwrap();
+        let snapshot = svc.snapshot_columns().await;
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.average_age(), Some(40.0));
+        assert_eq!(snapshot.age_range(), Some((20, 60)));
+        assert_eq!(snapshot.count_in_age_range(30, 60), 2);
+        assert_eq!(snapshot.count_by_domain()["b.com"], 2);
+    }
+
+    #[tokio::test]
+    async fn self_check_reports_a_drifted_email_index() {
+        let svc = service();
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(svc.self_check().await, HealthStatus::Healthy);
+
+        svc.tables.load().email_index.clear();
+        assert!(matches!(svc.self_check().await, HealthStatus::Degraded(_)));
+    }
+
+    #[tokio::test]
+    async fn canonical_normalizer_makes_gmail_aliases_collide() {
+        let svc = quiet()
+            .email_normalizer(EmailNormalizer::canonical())
+            .build();
+        svc.create_user(req("John", "john+spam@gmail.com", 30))
+            .await
+            .unwrap();
+        assert!(matches!(
+            svc.create_user(req("John", "j.ohn@gmail.com", 30)).await,
+            Err(DatabaseError::UserAlreadyExists)
+        ));
+
+        let plain = quiet().build();
+        plain
+            .create_user(req("John", "john+spam@gmail.com", 30))
+            .await
+            .unwrap();
+        plain
+            .create_user(req("John", "j.ohn@gmail.com", 30))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn recent_operations_keeps_only_the_newest_records() {
+        let svc = Arc::new(quiet().recent_ops_capacity(8).build());
+        let tasks: Vec<_> = (0..200)
+            .map(|i| {
+                let svc = Arc::clone(&svc);
+                tokio::spawn(async move {
+                    svc.create_user(req("Op", &format!("op{}@example.com", i), 30))
+                        .await
+                })
+            })
+            .collect();
+        future::join_all(tasks).await;
+        let last = svc
+            .create_user(req("Last", "last@example.com", 30))
+            .await
+            .unwrap();
+
+        let recent = svc.recent_operations(100);
+        assert_eq!(recent.len(), 8);
+        assert_eq!(recent[0].id.as_deref(), Some(last.id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_unaffected_by_later_mutations() {
+        let svc = service();
+        let ann = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let snapshot = svc.take_snapshot().await;
+
+        svc.delete_user(&ann.id).await.unwrap();
+        svc.create_user(req("Bob", "bob@example.com", 40))
+            .await
+            .unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].name, "Ann");
+    }
+
+    #[tokio::test]
+    async fn panicking_create_task_maps_to_task_panicked() {
+        let panicking: tokio::task::JoinHandle<Result<User, DatabaseError>> =
+            tokio::spawn(async { panic!("create exploded") });
+        let results = join_tasks([panicking]).await;
+        assert!(matches!(
+            &results[..],
+            [Err(DatabaseError::TaskPanicked(msg))] if msg == "create exploded"
+        ));
+
+        let cancelled: tokio::task::JoinHandle<Result<User, DatabaseError>> =
+            tokio::spawn(async { future::pending().await });
+        cancelled.abort();
+        let results = join_tasks([cancelled]).await;
+        assert!(matches!(&results[..], [Err(DatabaseError::TaskCancelled)]));
+    }
+
+    #[tokio::test]
+    async fn collecting_reporter_receives_bulk_progress() {
+        let reporter = Arc::new(CollectingReporter::default());
+        let svc = Arc::new(UserService::builder().reporter(reporter.clone()).build());
+        let requests = vec![req("Ann", "ann@example.com", 30)];
+        Arc::clone(&svc).bulk_create_users(requests).await.unwrap();
+        assert_eq!(
+            reporter.events(),
+            vec![
+                ProgressEvent::TransformStarted { count: 1 },
+                ProgressEvent::TransformFinished,
+                ProgressEvent::BatchStarted { batch: 1, size: 1 },
+                ProgressEvent::CreatingUser {
+                    name: "ANN".to_string()
+                },
+                ProgressEvent::BatchFinished { batch: 1 },
+                ProgressEvent::BulkFinished { batches: 1 },
+            ]
+        );
+    }
+
+    fn email_update(email: &str) -> UpdateUserRequest {
+        UpdateUserRequest {
+            name: None,
+            email: Some(email.to_string()),
+            age: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn email_history_lists_replaced_emails_oldest_first() {
+        let svc = service();
+        let user = svc
+            .create_user(req("Ann", "one@example.com", 30))
+            .await
+            .unwrap();
+        svc.update_user(&user.id, email_update("two@example.com"))
+            .await
+            .unwrap();
+        svc.update_user(&user.id, email_update("three@example.com"))
+            .await
+            .unwrap();
+
+        let history = svc.email_history(&user.id).await.unwrap();
+        let emails: Vec<&str> = history.iter().map(|(email, _)| email.as_str()).collect();
+        assert_eq!(emails, ["one@example.com", "two@example.com"]);
+        assert!(history[0].1 <= history[1].1);
+    }
+
+    #[tokio::test]
+    async fn validate_batch_reports_each_request_in_order() {
+        let svc = service();
+        let verdicts = svc.validate_batch(&[
+            req("Ann", "ann@example.com", 30),
+            req("", "nameless@example.com", 30),
+            req("Bob", "not-an-email", 30),
+            req("Cy", "cy@example.com", 150),
+            req("Di", "di@example.com", 40),
+        ]);
+        let ok: Vec<bool> = verdicts.iter().map(Result::is_ok).collect();
+        assert_eq!(ok, [true, false, false, false, true]);
+        assert!(svc.list_users().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_user_or_falls_back_on_a_miss() {
+        let svc = service();
+        let ann = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let mut fallback = ann.clone();
+        fallback.id = "fallback".to_string();
+        fallback.name = "Nobody".to_string();
+
+        assert_eq!(svc.get_user_or(&ann.id, fallback.clone()).await.name, "Ann");
+        assert_eq!(svc.get_user_or("missing", fallback).await.name, "Nobody");
+        assert!(svc.get_user_opt("missing").await.is_none());
+    }
+
+    /// A fresh path under the system temp dir; nothing is created there.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{}-{}", Uuid::new_v4(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    async fn seed(svc: &UserService, count: usize) -> Vec<User> {
+        let mut users = Vec::with_capacity(count);
+        for i in 0..count {
+            let email = format!("seed{}@example.com", i);
+            let age = 20 + (i % 60) as u8;
+            users.push(
+                svc.create_user(req(&format!("Seed {}", i), &email, age))
+                    .await
+                    .unwrap(),
+            );
+        }
+        users
+    }
+
+    #[tokio::test]
+    async fn sharded_export_partitions_the_table_without_duplicates() {
+        let svc = service();
+        let users = seed(&svc, 25).await;
+        let dir = temp_path("shards");
+        let counts = svc.export_sharded_csv(&dir, 4).await.unwrap();
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().sum::<usize>(), 25);
+
+        let mut exported = Vec::new();
+        for shard in 0..4 {
+            let path = format!("{}/users_shard_{}.csv", dir, shard);
+            exported.extend(read_csv_records(&path).await.unwrap().into_keys());
+        }
+        exported.sort();
+        let mut expected: Vec<String> = users.into_iter().map(|u| u.id).collect();
+        expected.sort();
+        assert_eq!(exported, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn creation_order_is_total_under_a_frozen_clock() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock).build();
+        let created = seed(&svc, 30).await;
+
+        let listed = svc.list_users_by_creation().await.unwrap();
+        let ids: Vec<&str> = listed.iter().map(|u| u.id.as_str()).collect();
+        let expected: Vec<&str> = created.iter().map(|u| u.id.as_str()).collect();
+        assert_eq!(ids, expected);
+        assert!(listed.windows(2).all(|w| w[0].sequence < w[1].sequence));
+    }
+
+    #[tokio::test]
+    async fn parallel_history_records_duration_and_item_count() {
+        let svc = service();
+        let requests: Vec<_> = (0..7)
+            .map(|i| req("Par", &format!("par{}@example.com", i), 30))
+            .collect();
+        Arc::clone(&svc).bulk_create_users(requests).await.unwrap();
+
+        let history = svc.parallel_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].kind, ParallelOpKind::BulkCreate);
+        assert_eq!(history[0].item_count, 7);
+        assert!(history[0].duration > Duration::ZERO);
+        assert_eq!(svc.get_stats().await.parallel_operations, 1);
+    }
+
+    #[tokio::test]
+    async fn emails_are_trimmed_and_inner_spaces_rejected() {
+        let svc = service();
+        let user = svc.create_user(req("Ann", " a@b.com ", 30)).await.unwrap();
+        assert_eq!(user.email.as_deref(), Some("a@b.com"));
+        for email in ["a b@c.com", "   ", ""] {
+            assert!(matches!(
+                svc.create_user(req("Bob", email, 30)).await,
+                Err(DatabaseError::ValidationError(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_budget_limits_in_flight_creates() {
+        let svc = service();
+        let big = |i: usize| CreateUserRequest {
+            name: "x".repeat(10_000),
+            ..req("", &format!("big{}@example.com", i), 30)
+        };
+        let cost = big(0).estimated_size();
+
+        let start = Instant::now();
+        let summary = Arc::clone(&svc)
+            .bulk_create_with_budget((0..4).map(big), cost + cost / 2)
+            .await;
+        assert_eq!((summary.created, summary.failed), (4, 0));
+        // Only one request fits the budget at a time, so the validation
+        // delays cannot overlap.
+        assert!(start.elapsed() >= VALIDATION_DELAY * 4);
+
+        // A request larger than the whole budget is still admitted alone.
+        let summary = Arc::clone(&svc)
+            .bulk_create_with_budget([big(9)], cost / 2)
+            .await;
+        assert_eq!(summary.created, 1);
+    }
+
+    /// `count` valid users built directly, for loading with `replace_dataset`
+    /// without paying the per-create validation delay.
+    fn generated_users(count: usize) -> Vec<User> {
+        let now = chrono::Utc::now();
+        (0..count)
+            .map(|i| User {
+                id: Uuid::new_v4().to_string(),
+                name: format!("Generated {}", i),
+                email: Some(format!("gen{}@example.com", i)),
+                age: Some(20 + (i % 60) as u8),
+                created_at: now,
+                updated_at: now,
+                email_history: Vec::new(),
+                sequence: i as u64,
+                name_updated_at: None,
+                email_updated_at: None,
+                age_updated_at: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn find_first_returns_a_match_or_none() {
+        let svc = service();
+        svc.replace_dataset(generated_users(100)).unwrap();
+        let found = svc.find_first(|u| u.age == Some(42)).await.unwrap();
+        assert_eq!(found.age, Some(42));
+        assert!(svc.find_first(|u| u.age == Some(99)).await.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    async fn bench_find_first_against_filter() {
+        let svc = service();
+        svc.replace_dataset(generated_users(1_000_000)).unwrap();
+        let start = Instant::now();
+        svc.find_first(|u| u.age == Some(42)).await.unwrap();
+        let short_circuit = start.elapsed();
+        let start = Instant::now();
+        let tables = svc.tables.load();
+        let filtered: Vec<User> = tables
+            .db
+            .par_iter()
+            .filter(|kv| kv.age == Some(42))
+            .map(|kv| kv.value().clone())
+            .collect();
+        assert!(filtered.into_iter().next().is_some());
+        let full_scan = start.elapsed();
+        println!(
+            "find_first {:?}, filter + next {:?}",
+            short_circuit, full_scan
+        );
+        assert!(short_circuit < full_scan);
+    }
+
+    #[tokio::test]
+    async fn age_only_update_moves_only_age_updated_at() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock.clone()).build();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        clock.advance(chrono::Duration::hours(1));
+        let update = UpdateUserRequest {
+            name: Some("Ann".to_string()),
+            email: Some("ann@example.com".to_string()),
+            age: Some(31),
+        };
+        let updated = svc.update_user(&user.id, update).await.unwrap();
+        assert_eq!(updated.age_updated_at, Some(at("2024-01-01T01:00:00Z")));
+        assert_eq!(updated.name_updated_at, None);
+        assert_eq!(updated.email_updated_at, None);
+    }
+
+    #[tokio::test]
+    async fn keyed_create_twice_returns_the_first_user() {
+        let svc = service();
+        let keyed = || CreateUserRequest {
+            idempotency_key: Some("signup-1".to_string()),
+            ..req("Ann", "ann@example.com", 30)
+        };
+        let first = svc.create_user(keyed()).await.unwrap();
+        let retry = svc.create_user(keyed()).await.unwrap();
+        assert_eq!(first.id, retry.id);
+        assert_eq!(svc.list_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn name_sort_is_ascending_and_stable_on_ties() {
+        let svc = service();
+        let first_bob = svc
+            .create_user(req("Bob", "bob1@example.com", 30))
+            .await
+            .unwrap();
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let second_bob = svc
+            .create_user(req("Bob", "bob2@example.com", 30))
+            .await
+            .unwrap();
+        svc.create_user(req("Cy", "cy@example.com", 30))
+            .await
+            .unwrap();
+
+        let sorted = svc.list_sorted(SortKey::Name, Order::Asc).await;
+        let names: Vec<&str> = sorted.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(names, ["Ann", "Bob", "Bob", "Cy"]);
+        assert_eq!(sorted[1].id, first_bob.id);
+        assert_eq!(sorted[2].id, second_bob.id);
+
+        let sorted = svc.list_sorted(SortKey::Name, Order::Desc).await;
+        assert_eq!(sorted[1].id, first_bob.id);
+    }
+
+    #[tokio::test]
+    async fn top_n_by_matches_the_head_of_a_full_sort() {
+        let svc = service();
+        svc.replace_dataset(generated_users(500)).unwrap();
+        for (key, order) in [(SortKey::Age, Order::Desc), (SortKey::Name, Order::Asc)] {
+            let top: Vec<String> = svc
+                .top_n_by(key, order, 5)
+                .await
+                .into_iter()
+                .map(|u| u.id)
+                .collect();
+            let sorted: Vec<String> = svc
+                .list_sorted(key, order)
+                .await
+                .into_iter()
+                .take(5)
+                .map(|u| u.id)
+                .collect();
+            assert_eq!(top, sorted);
+        }
+        assert!(svc.top_n_by(SortKey::Age, Order::Asc, 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    async fn bench_top_n_by_against_full_sort() {
+        let svc = service();
+        svc.replace_dataset(generated_users(1_000_000)).unwrap();
+        let start = Instant::now();
+        svc.top_n_by(SortKey::Age, Order::Desc, 10).await;
+        let heap = start.elapsed();
+        let start = Instant::now();
+        svc.list_sorted(SortKey::Age, Order::Desc)
+            .await
+            .truncate(10);
+        let sort = start.elapsed();
+        println!("top_n_by {:?}, full sort {:?}", heap, sort);
+        assert!(heap < sort);
+    }
+
+    #[tokio::test]
+    async fn csv_round_trip_keeps_commas_and_quotes_in_names() {
+        let escaped = CsvOptions {
+            double_quote: false,
+            ..CsvOptions::default()
+        };
+        for options in [CsvOptions::default(), escaped] {
+            let svc = service();
+            svc.create_user(req(r#"Doe, "Johnny""#, "johnny@example.com", 30))
+                .await
+                .unwrap();
+            let path = temp_path("quoting.csv");
+            svc.bulk_save_to_csv_with(&path, &options).await.unwrap();
+
+            let restored = service();
+            let summary = Arc::clone(&restored)
+                .bulk_load_from_csv_with(&path, &options)
+                .await
+                .unwrap();
+            assert_eq!(summary.loaded, 1);
+            let user = restored
+                .get_user_by_email("johnny@example.com")
+                .await
+                .unwrap();
+            assert_eq!(user.name, r#"Doe, "Johnny""#);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_count_follows_inserts_and_deletes() {
+        let svc = service();
+        let mut count = svc.watch_count();
+        assert_eq!(*count.borrow(), 0);
+
+        let ann = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert!(count.has_changed().unwrap());
+        assert_eq!(*count.borrow_and_update(), 1);
+        svc.create_user(req("Bob", "bob@example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(*count.borrow_and_update(), 2);
+        svc.delete_user(&ann.id).await.unwrap();
+        assert_eq!(*count.borrow_and_update(), 1);
+    }
+
+    #[tokio::test]
+    async fn every_format_round_trips_through_save_and_load() {
+        for format in [Format::Csv, Format::Json, Format::Ndjson] {
+            let svc = service();
+            seed(&svc, 3).await;
+            let path = temp_path("round-trip");
+            svc.save(&path, format).await.unwrap();
+
+            let restored = service();
+            let summary = Arc::clone(&restored).load(&path, format).await.unwrap();
+            assert_eq!((summary.loaded, summary.failed), (3, 0), "{:?}", format);
+            let user = restored
+                .get_user_by_email("seed1@example.com")
+                .await
+                .unwrap();
+            assert_eq!((user.name.as_str(), user.age), ("Seed 1", Some(21)));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_emails_on_load_follow_the_policy() {
+        let path = temp_path("duplicates.csv");
+        std::fs::write(
+            &path,
+            "name,email,age\nFirst,dup@example.com,30\nOther,other@example.com,30\nLast,DUP@example.com,40\n",
+        )
+        .unwrap();
+
+        for (policy, kept) in [
+            (DuplicateEmailPolicy::KeepFirst, "First"),
+            (DuplicateEmailPolicy::KeepLast, "Last"),
+        ] {
+            let svc = service();
+            let summary = Arc::clone(&svc)
+                .load_with_policy(&path, Format::Csv, policy)
+                .await
+                .unwrap();
+            assert_eq!(summary.loaded, 2);
+            assert_eq!(summary.duplicate_emails.len(), 1);
+            assert_eq!(
+                summary.duplicate_emails[0].occurrences,
+                [(0, None), (2, None)]
+            );
+            let user = svc.get_user_by_email("dup@example.com").await.unwrap();
+            assert_eq!(user.name, kept);
+        }
+
+        let svc = service();
+        let result = Arc::clone(&svc)
+            .load_with_policy(&path, Format::Csv, DuplicateEmailPolicy::Reject)
+            .await;
+        assert!(result.is_err());
+        assert!(svc.list_users().await.unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn gzip_stream_export_holds_every_row() {
+        let svc = service();
+        svc.replace_dataset(generated_users(20_000)).unwrap();
+        let path = temp_path("users.csv.gz");
+        let report = svc.stream_save_csv(&path, true).await.unwrap();
+        assert_eq!(report.rows, 20_000);
+
+        let file = tokio::io::BufReader::new(File::open(&path).await.unwrap());
+        let mut csv = String::new();
+        async_compression::tokio::bufread::GzipDecoder::new(file)
+            .read_to_string(&mut csv)
+            .await
+            .unwrap();
+        assert_eq!(csv.lines().count(), 20_001);
+        assert!(csv.starts_with("id,name,email,age,created_at,updated_at\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Runs the ignored test `name` in a child process started in `dir`, so
+    /// it has the process (and its peak RSS) to itself.
+    fn run_child_test(name: &str, dir: &std::path::Path) -> std::process::Output {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args([name, "--exact", "--ignored", "--nocapture"])
+            .current_dir(dir)
+            .output()
+            .unwrap()
+    }
+
+    /// Run in a child process by `gzip_stream_export_keeps_peak_memory_bounded`.
+    #[tokio::test]
+    #[ignore = "run by gzip_stream_export_keeps_peak_memory_bounded"]
+    async fn gzip_stream_export_child() {
+        let svc = quiet().shard_amount(128).unwrap().build();
+        svc.replace_dataset(generated_users(200_000)).unwrap();
+        let path = temp_path("users.csv.gz");
+        if !reset_peak_rss().await {
+            println!("peak RSS is not available; memory not asserted");
+            return;
+        }
+        let baseline = read_peak_rss().await.unwrap();
+        svc.stream_save_csv(&path, true).await.unwrap();
+        let growth = read_peak_rss().await.unwrap().saturating_sub(baseline);
+
+        // Sized afterwards, so decoding does not count towards the peak.
+        let file = tokio::io::BufReader::new(File::open(&path).await.unwrap());
+        let mut csv = Vec::new();
+        async_compression::tokio::bufread::GzipDecoder::new(file)
+            .read_to_end(&mut csv)
+            .await
+            .unwrap();
+        let csv_len = csv.len() as u64;
+        let _ = std::fs::remove_file(&path);
+        println!("csv {} bytes, peak RSS grew {} bytes", csv_len, growth);
+        // `stream_users` holds the ids of one of the 128 shards at a time; a
+        // buffered export would need at least the whole CSV.
+        assert!(
+            growth < csv_len / 5,
+            "grew {growth} for a {csv_len} byte CSV"
+        );
+    }
+
+    #[test]
+    fn gzip_stream_export_keeps_peak_memory_bounded() {
+        let output = run_child_test("tests::gzip_stream_export_child", &std::env::temp_dir());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+
+    #[tokio::test]
+    async fn age_is_optional_on_create() {
+        let svc = service();
+        let unknown = svc
+            .create_user(CreateUserRequest {
+                age: None,
+                ..req("Ann", "ann@example.com", 0)
+            })
+            .await
+            .unwrap();
+        assert_eq!(unknown.age, None);
+        let known = svc
+            .create_user(req("Bob", "bob@example.com", 120))
+            .await
+            .unwrap();
+        assert_eq!(known.age, Some(120));
+        assert!(
+            svc.create_user(req("Cy", "cy@example.com", 121))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_upsert_counts_overlapping_emails_as_updates() {
+        let svc = service();
+        svc.create_user(req("Old", "old@example.com", 30))
+            .await
+            .unwrap();
+        let summary = Arc::clone(&svc)
+            .bulk_upsert_users(vec![
+                req("A", "a@example.com", 30),
+                req("B", "b@example.com", 30),
+                req("A again", "A@example.com", 31),
+                req("Old renamed", "old@example.com", 32),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.created, summary.updated, summary.failed),
+            (2, 2, 0)
+        );
+        assert_eq!(svc.list_users().await.unwrap().len(), 3);
+        let old = svc.get_user_by_email("old@example.com").await.unwrap();
+        assert_eq!((old.name.as_str(), old.age), ("Old renamed", Some(32)));
+    }
+
+    #[tokio::test]
+    async fn rayon_blocking_matches_the_async_strategies() {
+        let batch = || {
+            vec![
+                req("alice", "alice@example.com", 30),
+                req("bob", "bob@example.com", 40),
+                req("bob again", "bob@example.com", 41),
+            ]
+        };
+        let mut outcomes = Vec::new();
+        for strategy in [
+            BulkStrategy::SpawnPerTask,
+            BulkStrategy::Bounded(2),
+            BulkStrategy::RayonBlocking,
+        ] {
+            let svc = Arc::new(quiet().bulk_strategy(strategy).build());
+            let mut events = svc.subscribe_events();
+            let results = Arc::clone(&svc).bulk_create_users(batch()).await.unwrap();
+            let mut names: Vec<_> = results
+                .iter()
+                .filter_map(|r| r.as_ref().ok().map(|u| u.name.clone()))
+                .collect();
+            names.sort();
+            let failed = results.iter().filter(|r| r.is_err()).count();
+            let mut created_events = 0;
+            while let Ok(event) = events.try_recv() {
+                assert!(matches!(event, UserEvent::Created(_)));
+                created_events += 1;
+            }
+            outcomes.push((
+                names,
+                failed,
+                created_events,
+                svc.list_users().await.unwrap().len(),
+            ));
+        }
+        assert_eq!(outcomes[0].1, 1);
+        assert_eq!(outcomes[0].2, 2);
+        assert!(outcomes.iter().all(|o| o == &outcomes[0]), "{outcomes:?}");
+    }
+
+    #[tokio::test]
+    async fn rayon_blocking_creates_honour_the_fault_injector() {
+        let svc = Arc::new(
+            quiet()
+                .bulk_strategy(BulkStrategy::RayonBlocking)
+                .fault_injector(FaultInjector::new(7).probability(1.0))
+                .build(),
+        );
+        let results = Arc::clone(&svc)
+            .bulk_create_users(vec![req("alice", "alice@example.com", 30)])
+            .await
+            .unwrap();
+        assert!(matches!(results[0], Err(DatabaseError::Injected)));
+        assert!(svc.list_users().await.unwrap().is_empty());
+        assert_eq!(svc.get_stats().await.create_failed, 1);
+    }
+
+    #[tokio::test]
+    async fn user_json_has_the_shape_of_the_user_derive() {
+        let svc = service();
+        let user = svc
+            .create_user(req("alice", "alice@example.com", 30))
+            .await
+            .unwrap();
+        let json = svc.get_user_json(&user.id).await.unwrap();
+        assert_eq!(json, serde_json::to_value(&user).unwrap());
+        assert_eq!(json["id"], user.id.as_str());
+        assert_eq!(json["email"], "alice@example.com");
+        assert_eq!(json["age"], 30);
+        let list = svc.list_users_json().await.unwrap();
+        assert_eq!(list.as_array().unwrap(), &vec![json]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_demo_runs_to_completion() {
+        let svc = service();
+        run_demo(Arc::clone(&svc), DemoScale::Small, LogFormat::Pretty).await;
+        let _ = std::fs::remove_file("users_export.csv");
+        assert!(svc.list_users().await.unwrap().len() > DemoScale::Small.user_count());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stats_logger_appends_one_line_per_interval() {
+        let svc = service();
+        let path = temp_path("stats.ndjson");
+        let logger = Arc::clone(&svc).spawn_stats_logger(&path, Duration::from_millis(100));
+        sleep(Duration::from_millis(550)).await;
+        logger.abort();
+        let _ = logger.await;
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!((3..=5).contains(&lines.len()), "{} lines", lines.len());
+        assert!(lines.iter().all(|line| line.get("timestamp").is_some()));
+    }
+
+    #[tokio::test]
+    async fn find_by_name_exact_returns_every_user_sharing_a_name() {
+        for indexed in [true, false] {
+            let svc = Arc::new(quiet().name_index(indexed).build());
+            let first = svc
+                .create_user(req("Sam Lee", "sam1@example.com", 30))
+                .await
+                .unwrap();
+            let second = svc
+                .create_user(req("sam lee", "sam2@example.com", 40))
+                .await
+                .unwrap();
+            svc.create_user(req("Sam Leeds", "sam3@example.com", 50))
+                .await
+                .unwrap();
+            let mut ids: Vec<_> = svc
+                .find_by_name_exact(" SAM LEE ")
+                .into_iter()
+                .map(|user| user.id)
+                .collect();
+            ids.sort();
+            let mut expected = vec![first.id.clone(), second.id.clone()];
+            expected.sort();
+            assert_eq!(ids, expected, "indexed: {indexed}");
+            svc.delete_user(&first.id).await.unwrap();
+            let remaining = svc.find_by_name_exact("Sam Lee");
+            assert_eq!(remaining.len(), 1);
+            assert_eq!(remaining[0].id, second.id);
+        }
+    }
+
+    #[tokio::test]
+    async fn preserved_email_case_is_shown_but_not_matched() {
+        let svc = Arc::new(quiet().preserve_email_case(true).build());
+        let user = svc
+            .create_user(req("alice", "Alice.Smith@Example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(user.email.as_deref(), Some("Alice.Smith@Example.com"));
+        let found = svc
+            .get_user_by_email("alice.smith@example.COM")
+            .await
+            .unwrap();
+        assert_eq!(found.id, user.id);
+        assert_eq!(found.email.as_deref(), Some("Alice.Smith@Example.com"));
+        let duplicate = svc
+            .create_user(req("other", "ALICE.SMITH@EXAMPLE.COM", 30))
+            .await;
+        assert!(matches!(duplicate, Err(DatabaseError::UserAlreadyExists)));
+
+        let lowered = service()
+            .create_user(req("bob", "Bob@Example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(lowered.email.as_deref(), Some("bob@example.com"));
+    }
+
+    #[tokio::test]
+    async fn migrate_all_bumps_every_age_and_keeps_stats_consistent() {
+        let svc = service();
+        let before = seed(&svc, 20).await;
+        let migrated = svc.migrate_all(|user| user.age = user.age.map(|age| age + 1));
+        assert_eq!(migrated, 20);
+        for user in &before {
+            let after = svc.get_user(&user.id).await.unwrap();
+            assert_eq!(after.age, user.age.map(|age| age + 1));
+        }
+        let stats = svc.get_stats().await;
+        assert_eq!(stats.update_count, 20);
+        assert_eq!(stats.parallel_operations, 1);
+        assert!(matches!(svc.self_check().await, HealthStatus::Healthy));
+    }
+
+    #[tokio::test]
+    async fn delayed_create_unblocks_a_waiting_reader() {
+        let svc = service();
+        let user = generated_users(1).remove(0);
+        let id = user.id.clone();
+        let writer = {
+            let svc = Arc::clone(&svc);
+            tokio::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                // Swap the dataset while the reader waits, so the create lands
+                // in tables the reader has not seen yet.
+                svc.replace_dataset(Vec::new()).unwrap();
+                svc.get_user_or_load(&user.id.clone(), || async { Ok(user) })
+                    .await
+            })
+        };
+        let found = svc
+            .get_user_await(&id, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(found.id, id);
+        writer.await.unwrap().unwrap();
+
+        let missing = svc
+            .get_user_await(&Uuid::new_v4().to_string(), Duration::from_millis(20))
+            .await;
+        assert!(matches!(missing, Err(DatabaseError::Timeout)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn awaited_reads_keep_waiting_through_a_delete() {
+        let svc = service();
+        let user = generated_users(1).remove(0);
+        let id = user.id.clone();
+        let mut waiters = JoinSet::new();
+        for _ in 0..8 {
+            let (svc, id) = (Arc::clone(&svc), id.clone());
+            waiters.spawn(async move { svc.get_user_await(&id, Duration::from_secs(10)).await });
+        }
+        // Insert and delete the user over and over, then leave it stored.
+        for _ in 0..200 {
+            let user = user.clone();
+            svc.get_user_or_load(&id, || async { Ok(user) })
+                .await
+                .unwrap();
+            svc.delete_user(&id).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        svc.get_user_or_load(&id, || async { Ok(user) })
+            .await
+            .unwrap();
+        while let Some(result) = waiters.join_next().await {
+            assert_eq!(result.unwrap().unwrap().id, id);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_json_holds_every_counter() {
+        let svc = service();
+        svc.create_user(req("alice", "alice@example.com", 30))
+            .await
+            .unwrap();
+        let stats = svc.get_stats().await;
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json().unwrap()).unwrap();
+        for field in [
+            "total_operations",
+            "create_count",
+            "read_count",
+            "update_count",
+            "delete_count",
+            "parallel_operations",
+            "create_failed",
+            "update_failed",
+            "delete_failed",
+            "validation_failed",
+        ] {
+            assert!(json[field].is_u64(), "missing {field}");
+        }
+        assert_eq!(json["create_count"], 1);
+        assert!(!format!("{stats}").contains('\n'));
+        assert!(format!("{stats:#}").lines().count() > 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ingest_workers_insert_everything_sent_before_close() {
+        let svc = service();
+        let (tx, workers) = Arc::clone(&svc).spawn_ingest_workers(100);
+        for i in 0..10_000 {
+            tx.send(req(
+                &format!("Ingest {i}"),
+                &format!("ingest{i}@example.com"),
+                30,
+            ))
+            .await
+            .unwrap();
+        }
+        drop(tx);
+        workers.await.unwrap();
+        assert_eq!(svc.list_users().await.unwrap().len(), 10_000);
+    }
+
+    fn name_update(name: &str) -> UpdateUserRequest {
+        UpdateUserRequest {
+            name: Some(name.to_string()),
+            email: None,
+            age: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_from_csv_follows_each_policy() {
+        let source = service();
+        let users = seed(&source, 2).await;
+        let (a, b) = (&users[0], &users[1]);
+        let old = temp_path("old.csv");
+        source.bulk_save_to_csv(&old).await.unwrap();
+        sleep(Duration::from_millis(5)).await;
+        source
+            .update_user(&a.id, name_update("Renamed In Backup"))
+            .await
+            .unwrap();
+        let new = temp_path("new.csv");
+        source.bulk_save_to_csv(&new).await.unwrap();
+
+        let empty = service();
+        let summary = Arc::clone(&empty)
+            .restore_from_csv(&old, RestorePolicy::FailIfNotEmpty)
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.restored, summary.skipped, summary.failed),
+            (2, 0, 0)
+        );
+        assert_eq!(empty.get_user(&a.id).await.unwrap().name, a.name);
+
+        // Holds the old backup with `b` edited since and one extra user.
+        let populated = || async {
+            let svc = service();
+            Arc::clone(&svc)
+                .restore_from_csv(&old, RestorePolicy::FailIfNotEmpty)
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(5)).await;
+            svc.update_user(&b.id, name_update("Edited Locally"))
+                .await
+                .unwrap();
+            svc.create_user(req("extra", "extra@example.com", 30))
+                .await
+                .unwrap();
+            svc
+        };
+
+        let svc = populated().await;
+        assert!(
+            Arc::clone(&svc)
+                .restore_from_csv(&new, RestorePolicy::FailIfNotEmpty)
+                .await
+                .is_err()
+        );
+
+        let svc = populated().await;
+        let summary = Arc::clone(&svc)
+            .restore_from_csv(&new, RestorePolicy::SkipExisting)
+            .await
+            .unwrap();
+        assert_eq!((summary.restored, summary.skipped), (0, 2));
+        assert_eq!(svc.get_user(&a.id).await.unwrap().name, a.name);
+        assert_eq!(svc.list_users().await.unwrap().len(), 3);
+
+        let svc = populated().await;
+        let summary = Arc::clone(&svc)
+            .restore_from_csv(&new, RestorePolicy::Merge)
+            .await
+            .unwrap();
+        assert_eq!((summary.restored, summary.skipped), (1, 1));
+        assert_eq!(svc.get_user(&a.id).await.unwrap().name, "Renamed In Backup");
+        assert_eq!(svc.get_user(&b.id).await.unwrap().name, "Edited Locally");
+        assert_eq!(svc.list_users().await.unwrap().len(), 3);
+
+        let svc = populated().await;
+        let summary = Arc::clone(&svc)
+            .restore_from_csv(&new, RestorePolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(summary.restored, 2);
+        assert_eq!(svc.list_users().await.unwrap().len(), 2);
+        assert_eq!(svc.get_user(&b.id).await.unwrap().name, b.name);
+        assert!(svc.get_user_by_email("extra@example.com").await.is_err());
+
+        let _ = std::fs::remove_file(&old);
+        let _ = std::fs::remove_file(&new);
+    }
+
+    #[tokio::test]
+    async fn quiet_verbosity_drops_only_per_item_events() {
+        for (verbosity, per_item) in [(Verbosity::Normal, 10), (Verbosity::Quiet, 0)] {
+            let reporter = Arc::new(CollectingReporter::default());
+            let svc = Arc::new(
+                UserService::builder()
+                    .reporter(reporter.clone())
+                    .verbosity(verbosity)
+                    .build(),
+            );
+            let requests = (0..10)
+                .map(|i| req(&format!("user {i}"), &format!("user{i}@example.com"), 30))
+                .collect();
+            svc.bulk_create_users(requests).await.unwrap();
+            let events = reporter.events();
+            let creating = events
+                .iter()
+                .filter(|e| matches!(e, ProgressEvent::CreatingUser { .. }))
+                .count();
+            assert_eq!(creating, per_item, "{verbosity:?}");
+            assert!(
+                events
+                    .iter()
+                    .any(|e| matches!(e, ProgressEvent::BulkFinished { .. }))
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    async fn bench_bulk_create_logging_on_and_off() {
+        let requests = || {
+            (0..20_000)
+                .map(|i| req(&format!("user {i}"), &format!("user{i}@example.com"), 30))
+                .collect::<Vec<_>>()
+        };
+        let mut timings = Vec::new();
+        for verbosity in [Verbosity::Normal, Verbosity::Quiet] {
+            let svc = Arc::new(UserService::builder().verbosity(verbosity).build());
+            let start = Instant::now();
+            svc.bulk_create_users(requests()).await.unwrap();
+            timings.push((verbosity, start.elapsed()));
+        }
+        println!("{:?}", timings);
+    }
+
+    #[tokio::test]
+    async fn compact_releases_capacity_after_mass_deletes() {
+        let svc = service();
+        let users = generated_users(10_000);
+        let ids: Vec<String> = users.iter().map(|user| user.id.clone()).collect();
+        svc.replace_dataset(users).unwrap();
+        for id in &ids[100..] {
+            svc.delete_user(id).await.unwrap();
+        }
+        let before = svc.tables.load().db.capacity();
+        let released = svc.compact();
+        let after = svc.tables.load().db.capacity();
+        assert!(after < before, "{after} >= {before}");
+        assert!(released > 0);
+        assert_eq!(svc.list_users().await.unwrap().len(), 100);
+        assert!(svc.get_user(&ids[0]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn repeated_email_in_one_batch_is_reported_as_a_duplicate() {
+        let batch = || {
+            vec![
+                req("first", "same@example.com", 30),
+                req("other", "other@example.com", 30),
+                req("second", "SAME@example.com", 30),
+            ]
+        };
+
+        let svc = Arc::new(
+            quiet()
+                .bulk_duplicate_policy(DuplicateEmailPolicy::KeepFirst)
+                .build(),
+        );
+        let results = Arc::clone(&svc).bulk_create_users(batch()).await.unwrap();
+        assert_eq!(results[0].as_ref().unwrap().name, "FIRST");
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(DatabaseError::DuplicateInBatch { kept: 0 })
+        ));
+
+        let svc = Arc::new(
+            quiet()
+                .bulk_duplicate_policy(DuplicateEmailPolicy::KeepLast)
+                .build(),
+        );
+        let results = Arc::clone(&svc).bulk_create_users(batch()).await.unwrap();
+        assert!(matches!(
+            results[0],
+            Err(DatabaseError::DuplicateInBatch { kept: 2 })
+        ));
+        assert_eq!(results[2].as_ref().unwrap().name, "SECOND");
+
+        let svc = Arc::new(
+            quiet()
+                .bulk_duplicate_policy(DuplicateEmailPolicy::Reject)
+                .build(),
+        );
+        let rejected = Arc::clone(&svc).bulk_create_users(batch()).await;
+        assert!(matches!(rejected, Err(DatabaseError::ValidationError(_))));
+        assert!(svc.list_users().await.unwrap().is_empty());
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<UserEvent>>,
+    }
+
+    impl RecordingSink {
+        /// Each recorded event as its kind and the user's id.
+        fn kinds(&self) -> Vec<(&'static str, String)> {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|event| match event {
+                    UserEvent::Created(user) => ("created", user.id.clone()),
+                    UserEvent::Updated(user) => ("updated", user.id.clone()),
+                    UserEvent::Deleted(user) => ("deleted", user.id.clone()),
+                })
+                .collect()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EventSink for RecordingSink {
+        async fn handle(
+            &self,
+            event: UserEvent,
+        ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            self.events.lock().unwrap().push(event);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sinks_receive_every_mutation_and_failures_are_ignored() {
+        let recording = Arc::new(RecordingSink::default());
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        let svc = quiet()
+            .event_sink(Arc::new(ChannelSink::new(tx)))
+            .event_sink(recording.clone())
+            .build();
+        let user = svc
+            .create_user(req("alice", "alice@example.com", 30))
+            .await
+            .unwrap();
+        svc.update_user(&user.id, name_update("Alice"))
+            .await
+            .unwrap();
+        svc.delete_user(&user.id).await.unwrap();
+        assert_eq!(
+            recording.kinds(),
+            vec![
+                ("created", user.id.clone()),
+                ("updated", user.id.clone()),
+                ("deleted", user.id.clone()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn injected_read_failures_go_through_the_retry_path() {
+        let user = generated_users(1).remove(0);
+        let reporter = Arc::new(CollectingReporter::default());
+        let svc = UserService::builder()
+            .reporter(reporter.clone())
+            .fault_injector(FaultInjector::new(1).fail_id(user.id.clone()))
+            .build();
+        svc.replace_dataset(vec![user.clone()]).unwrap();
+        let result = svc.complex_user_operation(&user.id).await;
+        assert!(matches!(result, Err(DatabaseError::Injected)));
+        let retries: Vec<u32> = reporter
+            .events()
+            .iter()
+            .filter_map(|e| match e {
+                ProgressEvent::Retry { attempt } => Some(*attempt),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(retries, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn search_stream_yields_the_batch_search_results() {
+        let svc = service();
+        svc.replace_dataset(generated_users(5_000)).unwrap();
+        let ids = |users: Vec<User>| {
+            let mut ids: Vec<String> = users.into_iter().map(|user| user.id).collect();
+            ids.sort();
+            ids
+        };
+        let batch = ids(svc.search_users_parallel("generated 1").await.unwrap());
+        let streamed = ids(svc.search_stream("GENERATED 1").collect().await);
+        assert_eq!(batch.len(), 1_111);
+        assert_eq!(streamed, batch);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bulk_input_order_makes_creation_order_follow_the_input() {
+        let recording = Arc::new(RecordingSink::default());
+        let svc = Arc::new(
+            quiet()
+                .bulk_input_order(true)
+                .event_sink(recording.clone())
+                .build(),
+        );
+        let names: Vec<String> = (0..300).map(|i| format!("USER {i:03}")).collect();
+        let requests = names
+            .iter()
+            .rev()
+            .map(|name| req(name, &format!("{}@example.com", name.replace(' ', "")), 30))
+            .collect();
+        let results = Arc::clone(&svc).bulk_create_users(requests).await.unwrap();
+        assert!(results.iter().all(Result::is_ok));
+        let listed: Vec<String> = svc
+            .list_sorted(SortKey::CreatedAt, Order::Asc)
+            .await
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        let expected: Vec<String> = names.into_iter().rev().collect();
+        assert_eq!(listed, expected);
+
+        // Events carry the stamped timestamps and sequences, not the ones
+        // the users had before stamping.
+        let events = recording.events.lock().unwrap().clone();
+        assert_eq!(events.len(), results.len());
+        for event in events {
+            let UserEvent::Created(announced) = event else {
+                panic!("unexpected event {event:?}");
+            };
+            let stored = svc.get_user(&announced.id).await.unwrap();
+            assert_eq!(
+                (
+                    announced.created_at,
+                    announced.updated_at,
+                    announced.sequence
+                ),
+                (stored.created_at, stored.updated_at, stored.sequence)
+            );
+        }
+    }
+
+    #[test]
+    fn stats_delta_subtracts_each_counter_and_saturates() {
+        let svc = quiet().build();
+        let earlier = ServiceStats {
+            total_operations: 10,
+            create_count: 4,
+            read_count: 3,
+            update_count: 2,
+            delete_count: 1,
+            parallel_operations: 0,
+            create_failed: 5,
+            update_failed: 0,
+            delete_failed: 0,
+            validation_failed: 7,
+        };
+        svc.apply_stat(|stats| *stats = earlier.clone());
+        svc.apply_stat(|stats| {
+            stats.create_count += 6;
+            stats.read_count += 1;
+            stats.parallel_operations += 2;
+            stats.validation_failed = 0;
+        });
+        let delta = svc.stats_delta(&earlier);
+        assert_eq!(delta.total_operations, 1);
+        assert_eq!(delta.create_count, 6);
+        assert_eq!(delta.read_count, 1);
+        assert_eq!(delta.update_count, 0);
+        assert_eq!(delta.delete_count, 0);
+        assert_eq!(delta.parallel_operations, 2);
+        assert_eq!(delta.create_failed, 0);
+        assert_eq!(delta.validation_failed, 0);
+    }
+
+    #[tokio::test]
+    async fn runtime_keeps_ticking_during_a_long_transform() {
+        let ticks = Arc::new(AtomicU64::new(0));
+        let ticker = {
+            let ticks = Arc::clone(&ticks);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_millis(5));
+                loop {
+                    interval.tick().await;
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        };
+        // Four chunks of slow items; on this single-threaded runtime the
+        // ticker only runs if the transform stays off the runtime thread.
+        let items: Vec<usize> = (0..BULK_BATCH_SIZE * 4).collect();
+        let started = ticks.load(Ordering::Relaxed);
+        let mapped = par_map_chunked(items, |i| {
+            if i % 100 == 0 {
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            i * 2
+        })
+        .await
+        .unwrap();
+        let during = ticks.load(Ordering::Relaxed) - started;
+        ticker.abort();
+        assert_eq!(mapped.len(), BULK_BATCH_SIZE * 4);
+        assert_eq!(mapped[7], 14);
+        assert!(during >= 5, "only {during} ticks");
+    }
+
+    #[tokio::test]
+    async fn other_tasks_progress_while_a_bulk_insert_runs() {
+        let svc = service();
+        let bulk_done = Arc::new(AtomicBool::new(false));
+        let finished_first = {
+            let bulk_done = Arc::clone(&bulk_done);
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    sleep(Duration::from_millis(1)).await;
+                }
+                !bulk_done.load(Ordering::SeqCst)
+            })
+        };
+        Arc::clone(&svc)
+            .bulk_insert_concurrent(20_000)
+            .await
+            .unwrap();
+        bulk_done.store(true, Ordering::SeqCst);
+        assert!(finished_first.await.unwrap());
+        assert_eq!(svc.list_users().await.unwrap().len(), 20_000);
+    }
+
+    #[test]
+    fn email_limits_accept_the_boundary_and_reject_one_past_it() {
+        let check = |svc: &UserService, email: String| {
+            svc.validate_batch(&[req("ann", &email, 30)]).remove(0)
+        };
+        let rejected = |result: Result<(), DatabaseError>, needle: &str| match result {
+            Err(DatabaseError::ValidationError(msg)) => msg.contains(needle),
+            _ => false,
+        };
+        let svc = quiet().build();
+        let local = |len: usize| "a".repeat(len);
+        // 64 + 1 + 189 = 254 bytes in total.
+        let domain = |len: usize| format!("{}.com", "d".repeat(len - 4));
+
+        assert!(check(&svc, format!("{}@example.com", local(64))).is_ok());
+        assert!(rejected(
+            check(&svc, format!("{}@example.com", local(65))),
+            "local part is 65 bytes"
+        ));
+        assert!(check(&svc, format!("{}@{}", local(64), domain(189))).is_ok());
+        assert!(rejected(
+            check(&svc, format!("{}@{}", local(64), domain(190))),
+            "255 bytes, longer than the limit of 254"
+        ));
+
+        let svc = quiet()
+            .email_limits(EmailLimits {
+                max_len: 254,
+                max_local_len: 64,
+                max_domain_len: 20,
+            })
+            .build();
+        assert!(check(&svc, format!("ann@{}", domain(20))).is_ok());
+        assert!(rejected(
+            check(&svc, format!("ann@{}", domain(21))),
+            "domain is 21 bytes"
+        ));
+    }
+
+    #[tokio::test]
+    async fn replace_clears_what_update_would_keep() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock.clone()).build();
+        let updated = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let replaced = svc
+            .create_user(req("Bob", "bob@example.com", 40))
+            .await
+            .unwrap();
+        clock.advance(chrono::Duration::minutes(1));
+
+        let partial = CreateUserRequest {
+            name: "New Name".to_string(),
+            email: Some("new@example.com".to_string()),
+            age: None,
+            idempotency_key: None,
+            country: None,
+        };
+        let after_update = svc
+            .update_user(
+                &updated.id,
+                UpdateUserRequest {
+                    name: Some("New Ann".to_string()),
+                    email: None,
+                    age: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(after_update.age, Some(30));
+        assert_eq!(after_update.email.as_deref(), Some("ann@example.com"));
+
+        let after_replace = svc.replace_user(&replaced.id, partial).await.unwrap();
+        assert_eq!(after_replace.id, replaced.id);
+        assert_eq!(after_replace.name, "New Name");
+        assert_eq!(after_replace.age, None);
+        assert_eq!(after_replace.email.as_deref(), Some("new@example.com"));
+        assert_eq!(after_replace.created_at, replaced.created_at);
+        assert_eq!(after_replace.updated_at, at("2024-01-01T00:01:00Z"));
+        assert!(svc.get_user_by_email("bob@example.com").await.is_err());
+        assert_eq!(
+            svc.get_user_by_email("new@example.com").await.unwrap().id,
+            replaced.id
+        );
+    }
+
+    #[test]
+    fn group_by_age_decade_counts_each_bucket() {
+        let svc = quiet().build();
+        let mut users = generated_users(600);
+        users[0].age = None;
+        svc.replace_dataset(users).unwrap();
+        let groups = svc.group_by(|user| user.age.map(|age| age / 10 * 10));
+        let mut counts: Vec<(Option<u8>, usize)> = groups
+            .iter()
+            .map(|(decade, users)| (*decade, users.len()))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![
+                (None, 1),
+                (Some(20), 99),
+                (Some(30), 100),
+                (Some(40), 100),
+                (Some(50), 100),
+                (Some(60), 100),
+                (Some(70), 100),
+            ]
+        );
+        assert!(
+            groups[&Some(40)]
+                .iter()
+                .all(|user| (40..50).contains(&user.age.unwrap()))
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidate_hook_fires_on_update_and_delete() {
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let svc = {
+            let invalidated = Arc::clone(&invalidated);
+            quiet()
+                .on_invalidate(move |id| invalidated.lock().unwrap().push(id.to_string()))
+                .build()
+        };
+        let ann = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let bob = svc
+            .create_user(req("Bob", "bob@example.com", 40))
+            .await
+            .unwrap();
+        assert!(invalidated.lock().unwrap().is_empty());
+
+        svc.update_user(&ann.id, name_update("Annie"))
+            .await
+            .unwrap();
+        assert_eq!(*invalidated.lock().unwrap(), vec![ann.id.clone()]);
+
+        svc.delete_user(&bob.id).await.unwrap();
+        assert_eq!(
+            *invalidated.lock().unwrap(),
+            vec![ann.id.clone(), bob.id.clone()]
+        );
+
+        assert!(svc.delete_user(&bob.id).await.is_err());
+        assert_eq!(invalidated.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn get_many_by_email_mixes_hits_misses_and_case() {
+        let svc = service();
+        let users = seed(&svc, 3).await;
+        let emails: Vec<String> = [
+            "SEED1@example.com",
+            "nobody@example.com",
+            "seed0@EXAMPLE.com",
+            "seed2@example.com",
+        ]
+        .map(String::from)
+        .to_vec();
+        let results = svc.get_many_by_email(&emails).await;
+        assert_eq!(results[0].as_ref().unwrap().id, users[1].id);
+        assert!(matches!(results[1], Err(DatabaseError::UserNotFound)));
+        assert_eq!(results[2].as_ref().unwrap().id, users[0].id);
+        assert_eq!(results[3].as_ref().unwrap().id, users[2].id);
+        assert_eq!(svc.get_stats().await.read_count, 3);
+        assert!(matches!(svc.self_check().await, HealthStatus::Healthy));
+    }
+
+    #[tokio::test]
+    async fn create_conflict_policies_resolve_a_taken_email() {
+        let svc = service();
+        let existing = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let again = || req("Annabel", "ANN@example.com", 41);
+
+        let error = svc
+            .create_user_with(again(), CreateConflictPolicy::Error)
+            .await;
+        assert!(matches!(error, Err(DatabaseError::UserAlreadyExists)));
+
+        let returned = svc
+            .create_user_with(again(), CreateConflictPolicy::ReturnExisting)
+            .await
+            .unwrap();
+        assert_eq!(returned.id, existing.id);
+        assert_eq!(returned.name, "Ann");
+        assert_eq!(returned.age, Some(30));
+
+        let updated = svc
+            .create_user_with(again(), CreateConflictPolicy::Update)
+            .await
+            .unwrap();
+        assert_eq!(updated.id, existing.id);
+        assert_eq!(updated.name, "Annabel");
+        assert_eq!(updated.age, Some(41));
+        assert_eq!(svc.list_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn diff_snapshots_reports_added_removed_and_changed_fields() {
+        let header = "id,name,email,age,created_at,updated_at\n";
+        let t0 = "2024-01-01T00:00:00Z";
+        let t1 = "2024-02-01T00:00:00Z";
+        let before = format!(
+            "{header}\
+             a,Ann,ann@example.com,30,{t0},{t0}\n\
+             b,Bob,bob@example.com,40,{t0},{t0}\n\
+             c,Cat,cat@example.com,50,{t0},{t0}\n"
+        );
+        let after = format!(
+            "{header}\
+             a,Ann,ann@example.com,30,{t0},{t0}\n\
+             b,Bobby,bob@example.com,41,{t0},{t1}\n\
+             d,Dan,,,{t1},{t1}\n"
+        );
+        let (a, b) = (temp_path("before.csv"), temp_path("after.csv"));
+        std::fs::write(&a, before).unwrap();
+        std::fs::write(&b, after).unwrap();
+        let diff = diff_snapshots(&a, &b).await.unwrap();
+        let _ = std::fs::remove_file(&a);
+        let _ = std::fs::remove_file(&b);
+        assert_eq!(
+            diff,
+            SnapshotDiff {
+                added: vec!["d".to_string()],
+                removed: vec!["c".to_string()],
+                changed: vec![("b".to_string(), vec!["name", "age", "updated_at"])],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_ids_outside_the_configured_format() {
+        let t0 = "2024-01-01T00:00:00Z";
+        let v4 = Uuid::new_v4();
+        let csv = format!(
+            "id,name,email,age,created_at,updated_at\n\
+             not-a-uuid,Ann,ann@example.com,30,{t0},{t0}\n\
+             {v4},Bob,bob@example.com,30,{t0},{t0}\n\
+             00000000-0000-0000-0000-000000000000,Cat,cat@example.com,30,{t0},{t0}\n\
+             user-42,Dan,dan@example.com,30,{t0},{t0}\n"
+        );
+        let path = temp_path("ids.csv");
+        std::fs::write(&path, csv).unwrap();
+        for (format, restored) in [
+            (IdFormat::Any, 4),
+            (IdFormat::Uuid { version: None }, 2),
+            (IdFormat::Uuid { version: Some(4) }, 1),
+            (
+                IdFormat::Pattern(regex::Regex::new(r"^user-\d+$").unwrap()),
+                1,
+            ),
+        ] {
+            let svc = Arc::new(quiet().id_format(format.clone()).build());
+            let summary = Arc::clone(&svc)
+                .restore_from_csv(&path, RestorePolicy::FailIfNotEmpty)
+                .await
+                .unwrap();
+            assert_eq!(summary.restored, restored, "{format:?}");
+            assert_eq!(summary.failed, 4 - restored, "{format:?}");
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn throttled_bulk_takes_about_count_over_rate() {
+        let svc = service();
+        let requests = (0..20)
+            .map(|i| req(&format!("user {i}"), &format!("user{i}@example.com"), 30))
+            .collect();
+        let summary = Arc::clone(&svc)
+            .bulk_create_throttled(requests, 100)
+            .await
+            .unwrap();
+        assert_eq!(summary.created, 20);
+        // Twenty ticks at 10ms, the first of which fires immediately.
+        assert!(
+            (Duration::from_millis(150)..Duration::from_millis(600)).contains(&summary.duration),
+            "{:?}",
+            summary.duration
+        );
+        assert!((50.0..=150.0).contains(&summary.achieved_per_sec));
+        assert!(matches!(
+            Arc::clone(&svc)
+                .bulk_create_throttled(vec![req("x", "x@example.com", 30)], 0)
+                .await,
+            Err(DatabaseError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn projection_export_writes_only_the_chosen_columns() {
+        let svc = service();
+        svc.replace_dataset(generated_users(BULK_BATCH_SIZE + 7))
+            .unwrap();
+        let path = temp_path("emails.csv");
+        svc.export_projection_csv(&path, &[Field::Email])
+            .await
+            .unwrap();
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("email"));
+        let rows: HashSet<&str> = lines.collect();
+        assert_eq!(rows.len(), BULK_BATCH_SIZE + 7);
+        assert!(rows.contains("gen0@example.com"));
+        assert!(svc.export_projection_csv(&path, &[]).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_counter_increments_are_not_lost() {
+        let svc = service();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let mut tasks = JoinSet::new();
+        for _ in 0..50 {
+            let svc = Arc::clone(&svc);
+            let id = user.id.clone();
+            tasks.spawn(async move {
+                for _ in 0..200 {
+                    svc.increment_user_counter(&id, "logins", 1).unwrap();
+                    tokio::task::yield_now().await;
+                }
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap();
+        }
+        assert_eq!(svc.get_user_counter(&user.id, "logins").unwrap(), 10_000);
+        assert_eq!(svc.get_user_counter(&user.id, "other").unwrap(), 0);
+        svc.delete_user(&user.id).await.unwrap();
+        assert!(svc.get_user_counter(&user.id, "logins").is_err());
+    }
+
+    #[test]
+    fn age_deserializes_from_a_number_or_a_numeric_string() {
+        let parse = |json: &str| serde_json::from_str::<CreateUserRequest>(json).map(|r| r.age);
+        assert_eq!(parse(r#"{"name":"Ann","age":"30"}"#).unwrap(), Some(30));
+        assert_eq!(parse(r#"{"name":"Ann","age":30}"#).unwrap(), Some(30));
+        assert_eq!(parse(r#"{"name":"Ann"}"#).unwrap(), None);
+        let err = parse(r#"{"name":"Ann","age":"old"}"#).unwrap_err();
+        assert!(
+            err.to_string().contains("age must be a whole number"),
+            "{err}"
+        );
+        assert!(parse(r#"{"name":"Ann","age":300}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn user_batches_have_the_requested_size() {
+        let svc = service();
+        svc.replace_dataset(generated_users(25)).unwrap();
+        let batches: Vec<Vec<User>> = svc.stream_user_batches(10).collect().await;
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![10, 10, 5]);
+        let ids: HashSet<String> = batches.into_iter().flatten().map(|user| user.id).collect();
+        assert_eq!(ids.len(), 25);
+        assert_eq!(svc.stream_user_batches(0).count().await, 25);
+    }
+
+    #[tokio::test]
+    async fn collect_all_reports_every_violation() {
+        let bad = || CreateUserRequest {
+            name: String::new(),
+            email: Some("no-at-sign".to_string()),
+            age: Some(200),
+            idempotency_key: None,
+            country: None,
+        };
+        let message = |result: Result<User, DatabaseError>| match result {
+            Err(DatabaseError::ValidationError(msg)) => msg,
+            other => panic!("expected a validation error, got {other:?}"),
+        };
+
+        let svc = quiet().validation_mode(ValidationMode::CollectAll).build();
+        let all = message(svc.create_user(bad()).await);
+        assert_eq!(
+            all.split("; ").collect::<Vec<_>>(),
+            vec![
+                "Name cannot be empty",
+                "Invalid email format",
+                "Age must be between 13 and 120",
+            ]
+        );
+
+        let svc = quiet().build();
+        assert_eq!(
+            message(svc.create_user(bad()).await),
+            "Name cannot be empty"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_misses_share_one_loader_call() {
+        let svc = service();
+        let user = generated_users(1).remove(0);
+        let calls = Arc::new(AtomicU64::new(0));
+        let mut tasks = JoinSet::new();
+        for _ in 0..20 {
+            let svc = Arc::clone(&svc);
+            let user = user.clone();
+            let calls = Arc::clone(&calls);
+            tasks.spawn(async move {
+                svc.get_user_or_load(&user.id.clone(), || async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    sleep(Duration::from_millis(50)).await;
+                    Ok(user)
+                })
+                .await
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            assert_eq!(result.unwrap().unwrap().id, user.id);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(svc.list_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_failed_load_hands_over_to_one_loader_at_a_time() {
+        let svc = service();
+        let user = generated_users(1).remove(0);
+        let calls = Arc::new(AtomicU64::new(0));
+        let active = Arc::new(AtomicU64::new(0));
+        let max_active = Arc::new(AtomicU64::new(0));
+        let mut tasks = JoinSet::new();
+        for round in 0..16 {
+            let svc = Arc::clone(&svc);
+            let user = user.clone();
+            let (calls, active, max_active) = (
+                Arc::clone(&calls),
+                Arc::clone(&active),
+                Arc::clone(&max_active),
+            );
+            tasks.spawn(async move {
+                // Later callers arrive while the first loader fails and the
+                // next one runs.
+                sleep(Duration::from_millis(5 * round)).await;
+                svc.get_user_or_load(&user.id.clone(), || async move {
+                    let attempt = calls.fetch_add(1, Ordering::SeqCst);
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    sleep(Duration::from_millis(30)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    if attempt == 0 {
+                        Err(DatabaseError::Timeout)
+                    } else {
+                        Ok(user)
+                    }
+                })
+                .await
+            });
+        }
+        let mut failed = 0;
+        while let Some(result) = tasks.join_next().await {
+            match result.unwrap() {
+                Ok(loaded) => assert_eq!(loaded.id, user.id),
+                Err(_) => failed += 1,
+            }
+        }
+        assert_eq!(failed, 1);
+        assert_eq!(max_active.load(Ordering::SeqCst), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(svc.loads_in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_rotation_keeps_the_newest_and_loads_the_latest() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock.clone()).snapshot_retention(3).build();
+        let dir = temp_path("snapshots");
+        let mut saved = Vec::new();
+        for i in 0..5 {
+            svc.create_user(req(
+                &format!("user {i}"),
+                &format!("user{i}@example.com"),
+                30,
+            ))
+            .await
+            .unwrap();
+            saved.push(svc.save_snapshot(&dir).await.unwrap());
+            clock.advance(chrono::Duration::seconds(1));
+        }
+        let remaining = list_snapshots(&dir).await.unwrap();
+        assert_eq!(remaining, saved[2..].to_vec());
+
+        let restored = service();
+        let summary = Arc::clone(&restored)
+            .load_latest_snapshot(&dir)
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(summary.restored, 5);
+        assert_eq!(restored.dataset_hash(), svc.dataset_hash());
+    }
+
+    #[test]
+    fn shard_sizes_are_roughly_even() {
+        let svc = quiet().build();
+        svc.replace_dataset(generated_users(50_000)).unwrap();
+        let sizes = svc.shard_sizes();
+        assert_eq!(sizes.iter().sum::<usize>(), 50_000);
+        let mean = 50_000 / sizes.len();
+        assert!(
+            sizes
+                .iter()
+                .all(|&size| size > mean / 2 && size < mean * 3 / 2),
+            "mean {mean}, sizes {sizes:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn dataset_hash_matches_for_equal_data_and_changes_after_a_mutation() {
+        let users = generated_users(1_000);
+        let primary = service();
+        primary.replace_dataset(users.clone()).unwrap();
+        let replica = Arc::new(quiet().shard_amount(8).unwrap().build());
+        replica
+            .replace_dataset(users.into_iter().rev().collect())
+            .unwrap();
+        assert_eq!(primary.dataset_hash(), replica.dataset_hash());
+
+        let id = replica.list_users().await.unwrap()[0].id.clone();
+        replica
+            .update_user(&id, name_update("Changed"))
+            .await
+            .unwrap();
+        assert_ne!(primary.dataset_hash(), replica.dataset_hash());
+    }
+
+    #[tokio::test]
+    async fn users_without_email_are_stored_and_searchable() {
+        let no_email = |name: &str| CreateUserRequest {
+            name: name.to_string(),
+            email: None,
+            age: Some(30),
+            idempotency_key: None,
+            country: None,
+        };
+        let svc = quiet().email_required(false).build();
+        let first = svc.create_user(no_email("Quiet Person")).await.unwrap();
+        let second = svc.create_user(no_email("Quiet Twin")).await.unwrap();
+        assert_eq!(first.email, None);
+        let mut found: Vec<String> = svc
+            .search_users_parallel("quiet")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+        found.sort();
+        let mut expected = vec![first.id, second.id];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(matches!(svc.self_check().await, HealthStatus::Healthy));
+
+        let strict = service();
+        assert!(matches!(
+            strict.create_user(no_email("Nobody")).await,
+            Err(DatabaseError::ValidationError(_))
+        ));
+    }
+
+    /// Wall time and memory of one strategy run by `benchmark_strategies`.
+    #[derive(Debug, Clone)]
+    struct StrategyBenchmark {
+        strategy: BulkStrategy,
+        created: usize,
+        failed: usize,
+        duration: Duration,
+        /// Peak resident set size during the run. Only reported on Linux, where
+        /// the peak can be reset between runs through `/proc/self/clear_refs`.
+        peak_rss_bytes: Option<u64>,
+    }
+
+    /// Runs the same `count` inserts through each strategy on a fresh, quiet
+    /// service and reports how long each took. The services are dropped between
+    /// runs so one strategy's users do not count towards the next one's memory.
+    async fn benchmark_strategies(
+        count: usize,
+        strategies: &[BulkStrategy],
+    ) -> Vec<StrategyBenchmark> {
+        let mut reports = Vec::with_capacity(strategies.len());
+        for &strategy in strategies {
+            let service = Arc::new(
+                UserService::builder()
+                    .verbosity(Verbosity::Quiet)
+                    .reporter(Arc::new(SilentReporter))
+                    .bulk_strategy(strategy)
+                    .build(),
+            );
+            let requests = (0..count).map(|i| CreateUserRequest {
+                name: format!("BenchUser{}", i),
+                email: Some(format!("bench{}@bench.com", i)),
+                age: Some(20 + (i % 80) as u8),
+                idempotency_key: None,
+                country: None,
+            });
+            let tracks_peak = reset_peak_rss().await;
+            let start = Instant::now();
+            let summary = Arc::clone(&service).bulk_create_from_iter(requests).await;
+            let duration = start.elapsed();
+            let peak_rss_bytes = if tracks_peak {
+                read_peak_rss().await
+            } else {
+                None
+            };
+            drop(service);
+            reports.push(StrategyBenchmark {
+                strategy,
+                created: summary.created,
+                failed: summary.failed,
+                duration,
+                peak_rss_bytes,
+            });
+        }
+        reports
+    }
+
+    /// Resets the kernel's peak RSS counter for this process. Returns false
+    /// where that is not supported, in which case the peak is not reported.
+    async fn reset_peak_rss() -> bool {
+        tokio::fs::write("/proc/self/clear_refs", "5").await.is_ok()
+    }
+
+    async fn read_peak_rss() -> Option<u64> {
+        let status = tokio::fs::read_to_string("/proc/self/status").await.ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kb * 1024)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    async fn bench_bulk_strategies() {
+        let strategies = [
+            BulkStrategy::SpawnPerTask,
+            BulkStrategy::Bounded(256),
+            BulkStrategy::RayonBlocking,
+        ];
+        for report in benchmark_strategies(1_000_000, &strategies).await {
+            let peak = report.peak_rss_bytes.map_or("n/a".to_string(), |b| {
+                format!("{:.1} MiB", b as f64 / 1048576.0)
+            });
+            println!(
+                "{:?}: {} created, {} failed in {:?} | peak RSS {}",
+                report.strategy, report.created, report.failed, report.duration, peak
+            );
+            assert_eq!(report.created, 1_000_000);
+        }
+    }
+
+    #[tokio::test]
+    async fn projection_returns_only_the_requested_fields() {
+        let svc = service();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let fields = Field::parse_list("name, email,name").unwrap();
+        assert_eq!(fields, vec![Field::Name, Field::Email]);
+        let projected = svc.get_user_projected(&user.id, &fields).await.unwrap();
+        assert_eq!(
+            projected,
+            serde_json::json!({"name": "Ann", "email": "ann@example.com"})
+        );
+
+        let err = Field::parse_list("name,password,ssn").unwrap_err();
+        assert!(err.to_string().contains("password, ssn"), "{err}");
+        assert!(Field::parse_list(" , ").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn readers_never_see_a_partially_swapped_dataset() {
+        let svc = service();
+        let dataset = |tag: &str| {
+            let mut users = generated_users(2_000);
+            for user in &mut users {
+                user.name = format!("{tag} {}", user.name);
+            }
+            users
+        };
+        svc.replace_dataset(dataset("blue")).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let svc = Arc::clone(&svc);
+            let done = Arc::clone(&done);
+            tokio::spawn(async move {
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let users = svc.list_users().await.unwrap();
+                    assert_eq!(users.len(), 2_000);
+                    let tag = users[0].name.split(' ').next().unwrap().to_string();
+                    assert!(users.iter().all(|user| user.name.starts_with(&tag)));
+                    reads += 1;
+                    tokio::task::yield_now().await;
+                }
+                reads
+            })
+        };
+        for i in 0..20 {
+            let tag = if i % 2 == 0 { "green" } else { "blue" };
+            let svc = Arc::clone(&svc);
+            let users = dataset(tag);
+            tokio::task::spawn_blocking(move || svc.replace_dataset(users).unwrap())
+                .await
+                .unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        assert!(reader.await.unwrap() > 0);
     }
 
-    pub async fn bulk_save_to_csv(
-        &self,
-        path: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let start = Instant::now();
-        let users = self.list_users().await?;
-        let serialize_start = Instant::now();
+    #[tokio::test]
+    async fn dropping_a_create_mid_validation_leaves_no_index_entry() {
+        let svc = service();
+        let dropped = tokio::time::timeout(
+            VALIDATION_DELAY / 2,
+            svc.create_user(req("Ann", "ann@example.com", 30)),
+        )
+        .await;
+        assert!(dropped.is_err());
+        let tables = svc.tables.load();
+        assert!(tables.db.is_empty());
+        assert!(tables.email_index.is_empty());
+        assert!(matches!(svc.self_check().await, HealthStatus::Healthy));
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+    }
 
-        let mut wtr = csv::Writer::from_writer(vec![]);
-        for user in &users {
-            wtr.serialize(user)?;
-        }
-        let serialized_data = wtr.into_inner()?;
-        let write_start = Instant::now();
+    #[tokio::test]
+    async fn string_ages_survive_an_export_and_import_round_trip() {
+        let svc = service();
+        seed(&svc, 3).await;
+        let ages = |users: Vec<User>| {
+            let mut ages: Vec<Option<u8>> = users.into_iter().map(|user| user.age).collect();
+            ages.sort();
+            ages
+        };
+        let expected = ages(svc.list_users().await.unwrap());
+        let quoted = JsonOptions {
+            age_as_string: true,
+        };
 
-        let mut file = File::create(path).await?;
-        file.write_all(&serialized_data).await?;
-        file.flush().await?;
+        let json = temp_path("users.json");
+        svc.bulk_save_to_json_with(&json, Format::Json, quoted)
+            .await
+            .unwrap();
+        let shape: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        assert!(shape[0]["age"].is_string());
+        let loaded = service();
+        Arc::clone(&loaded)
+            .bulk_load_from_json(&json)
+            .await
+            .unwrap();
+        assert_eq!(ages(loaded.list_users().await.unwrap()), expected);
 
-        let duration = start.elapsed();
-        let count = users.len();
+        let ndjson = temp_path("users.ndjson");
+        svc.bulk_save_to_json_with(&ndjson, Format::Ndjson, quoted)
+            .await
+            .unwrap();
+        let loaded = service();
+        Arc::clone(&loaded)
+            .bulk_load_from_ndjson(&ndjson)
+            .await
+            .unwrap();
+        assert_eq!(ages(loaded.list_users().await.unwrap()), expected);
 
-        println!(
-            "✅ Saved {} users to {} in {:?} (serialize: {:?}, write: {:?})",
-            count,
-            path,
-            duration,
-            serialize_start.elapsed(),
-            write_start.elapsed()
+        let merged = service();
+        let file = File::open(&ndjson).await.unwrap();
+        let summary = Arc::clone(&merged)
+            .merge_import_ndjson_stream(file)
+            .await
+            .unwrap();
+        assert_eq!((summary.created, summary.failed), (3, 0));
+        assert_eq!(ages(merged.list_users().await.unwrap()), expected);
+
+        svc.bulk_save_to_json_with(&json, Format::Json, JsonOptions::default())
+            .await
+            .unwrap();
+        let shape: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        assert!(shape[0]["age"].is_u64());
+        let _ = std::fs::remove_file(&json);
+        let _ = std::fs::remove_file(&ndjson);
+    }
+
+    #[tokio::test]
+    async fn find_invalid_reports_a_user_stored_past_validation() {
+        let svc = service();
+        let valid = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let mut broken = generated_users(1).remove(0);
+        broken.age = Some(200);
+        broken.name = String::new();
+        svc.tables
+            .load()
+            .db
+            .insert(broken.id.clone(), broken.clone());
+        let invalid = svc.find_invalid();
+        assert_eq!(invalid.len(), 1);
+        let (id, violations) = &invalid[0];
+        assert_eq!(id, &broken.id);
+        assert_ne!(id, &valid.id);
+        assert!(
+            violations
+                .iter()
+                .any(|v| v.starts_with("Age must be between"))
         );
-        Ok(())
+        assert!(violations.iter().any(|v| v == "Name cannot be empty"));
     }
 
-    pub async fn bulk_load_from_csv(
-        self: Arc<Self>,
-        path: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let start = Instant::now();
+    #[tokio::test]
+    async fn transactional_bulk_with_one_bad_row_inserts_nothing() {
+        let svc = Arc::new(quiet().bulk_transactional(true).build());
+        let requests = vec![
+            req("ann", "ann@example.com", 30),
+            req("bob", "not-an-email", 30),
+            req("cat", "cat@example.com", 30),
+        ];
+        match Arc::clone(&svc).bulk_create_users(requests).await {
+            Err(DatabaseError::BatchRejected { violations }) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].0, 1);
+            }
+            other => panic!("expected BatchRejected, got {other:?}"),
+        }
+        assert!(svc.list_users().await.unwrap().is_empty());
+        assert!(svc.tables.load().email_index.is_empty());
 
-        let mut file = File::open(path).await?;
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).await?;
-        let read_duration = start.elapsed();
+        let results = Arc::clone(&svc)
+            .bulk_create_users(vec![req("ann", "ann@example.com", 30)])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+    }
 
-        let cursor = std::io::Cursor::new(contents);
-        let mut rdr = csv::Reader::from_reader(cursor);
+    /// Insert and lookup times of one hasher measured by `benchmark_hashers`.
+    #[derive(Debug, Clone)]
+    struct HasherBenchmark {
+        hasher: MapHasher,
+        insert: Duration,
+        get: Duration,
+    }
 
-        let parse_start = Instant::now();
-        let mut requests = Vec::new();
-        for result in rdr.deserialize() {
-            let req: CreateUserRequest =
-                result.map_err(|e| format!("CSV deserialize error: {}", e))?;
-            requests.push(req);
+    /// Inserts `count` UUID-keyed entries into a fresh map per hasher from the
+    /// rayon pool, then looks every key up again, and times both phases.
+    fn benchmark_hashers(count: usize, hashers: &[MapHasher]) -> Vec<HasherBenchmark> {
+        let keys: Vec<String> = (0..count)
+            .into_par_iter()
+            .map(|_| Uuid::new_v4().to_string())
+            .collect();
+        hashers
+            .iter()
+            .map(|&hasher| {
+                let map = new_map::<String, usize>(None, hasher);
+                let start = Instant::now();
+                keys.par_iter().enumerate().for_each(|(i, key)| {
+                    map.insert(key.clone(), i);
+                });
+                let insert = start.elapsed();
+                let start = Instant::now();
+                let found = keys.par_iter().filter(|key| map.contains_key(*key)).count();
+                let get = start.elapsed();
+                debug_assert_eq!(found, count);
+                HasherBenchmark {
+                    hasher,
+                    insert,
+                    get,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    fn bench_hashers() {
+        for report in benchmark_hashers(1_000_000, &[MapHasher::SipHash, MapHasher::Fx]) {
+            println!(
+                "{:?}: insert {:?}, get {:?}",
+                report.hasher, report.insert, report.get
+            );
         }
-        let parse_duration = parse_start.elapsed();
+    }
 
-        println!(
-            "🚀 Loading {} users from {}... (read: {:?}, parse: {:?})",
-            requests.len(),
-            path,
-            read_duration,
-            parse_duration
+    #[tokio::test]
+    async fn fx_hasher_only_applies_to_the_id_table() {
+        let svc = quiet().hasher(MapHasher::Fx).name_index(true).build();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let check = |tables: &Tables| {
+            assert_eq!(tables.db.hasher().kind, MapHasher::Fx);
+            assert_eq!(tables.email_index.hasher().kind, MapHasher::SipHash);
+            let names = tables.name_index.as_ref().unwrap();
+            assert_eq!(names.hasher().kind, MapHasher::SipHash);
+        };
+        check(&svc.tables.load());
+        assert_eq!(svc.get_user(&user.id).await.unwrap().id, user.id);
+        assert_eq!(
+            svc.get_user_by_email("ann@example.com").await.unwrap().id,
+            user.id
         );
 
-        let insert_start = Instant::now();
-        let handles = requests.into_iter().map(|req| {
-            let service = Arc::clone(&self);
+        svc.replace_dataset(generated_users(10)).unwrap();
+        check(&svc.tables.load());
+    }
+
+    #[tokio::test]
+    async fn operation_hook_sees_a_create_and_a_failed_get() {
+        let metrics = Arc::new(Mutex::new(Vec::new()));
+        let svc = {
+            let metrics = Arc::clone(&metrics);
+            quiet()
+                .on_operation(move |metric| metrics.lock().unwrap().push(metric))
+                .build()
+        };
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        assert!(svc.get_user("missing").await.is_err());
+        let metrics = metrics.lock().unwrap().clone();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(
+            (metrics[0].kind, metrics[0].success),
+            (OpKind::Create, true)
+        );
+        // Creates include the simulated validation delay.
+        assert!(metrics[0].duration >= VALIDATION_DELAY);
+        assert_eq!((metrics[1].kind, metrics[1].success), (OpKind::Read, false));
+        assert!(metrics[1].duration < metrics[0].duration);
+    }
+
+    #[tokio::test]
+    async fn csv_save_retries_until_the_target_becomes_writable() {
+        let reporter = Arc::new(CollectingReporter::default());
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(100),
+        };
+        let svc = UserService::builder()
+            .reporter(reporter.clone())
+            .save_retry(retry)
+            .build();
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+
+        // The first attempt fails because the directory does not exist yet;
+        // it appears while the service waits to retry.
+        let dir = temp_path("late-dir");
+        let path = format!("{dir}/users.csv");
+        let create_dir = {
+            let dir = dir.clone();
             tokio::spawn(async move {
-                let _ = service.create_user(req).await;
+                sleep(Duration::from_millis(30)).await;
+                tokio::fs::create_dir(&dir).await.unwrap();
             })
-        });
+        };
+        svc.bulk_save_to_csv(&path).await.unwrap();
+        create_dir.await.unwrap();
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .contains("ann@example.com")
+        );
+        let retries = reporter
+            .events()
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::Retry { .. }))
+            .count();
+        assert_eq!(retries, 1);
+        let _ = std::fs::remove_dir_all(&dir);
 
-        let _ = futures::future::join_all(handles).await;
-        let total_duration = start.elapsed();
+        let never = format!("{}/users.csv", temp_path("missing-dir"));
+        let err = svc.bulk_save_to_csv(&never).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DatabaseError>(),
+            Some(DatabaseError::Io(_))
+        ));
+    }
 
-        println!(
-            "✅ Loaded {} users from {} in {:?} (insert: {:?})",
-            self.db.len(),
-            path,
-            total_duration,
-            insert_start.elapsed()
+    #[tokio::test]
+    async fn merge_import_keeps_the_newest_version_of_each_user() {
+        let svc = service();
+        let stored = generated_users(2);
+        svc.replace_dataset(stored.clone()).unwrap();
+
+        let mut newer = stored[0].clone();
+        newer.name = "Newer Name".to_string();
+        newer.updated_at = stored[0].updated_at + chrono::Duration::minutes(5);
+        let mut older = stored[1].clone();
+        older.name = "Older Name".to_string();
+        older.updated_at = stored[1].updated_at - chrono::Duration::minutes(5);
+        let mut fresh = generated_users(3).remove(2);
+        fresh.email = Some("fresh@example.com".to_string());
+        let mut feed = String::new();
+        for user in [&newer, &older, &fresh] {
+            feed.push_str(&serde_json::to_string(user).unwrap());
+            feed.push_str("\n\n");
+        }
+        feed.push_str("{not json\n");
+
+        let summary = Arc::clone(&svc)
+            .merge_import_ndjson_stream(feed.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            (
+                summary.created,
+                summary.updated,
+                summary.skipped,
+                summary.failed
+            ),
+            (1, 1, 1, 1)
         );
-        Ok(())
+        assert_eq!(svc.get_user(&newer.id).await.unwrap().name, "Newer Name");
+        assert_eq!(svc.get_user(&older.id).await.unwrap().name, stored[1].name);
+        assert_eq!(svc.get_user(&fresh.id).await.unwrap().email, fresh.email);
     }
 
-    pub async fn complex_user_operation(&self, id: &str) -> Result<User, DatabaseError> {
-        const MAX_RETRIES: u32 = 3;
-        const RETRY_DELAY: Duration = Duration::from_millis(100);
-
-        for attempt in 1..=MAX_RETRIES {
-            match self.get_user(id).await {
-                Ok(mut user) => {
-                    sleep(Duration::from_millis(50)).await;
-                    user.name = format!("Processed: {}", user.name);
-                    let update_req = UpdateUserRequest {
-                        name: Some(user.name.clone()),
-                        email: None,
-                        age: None,
-                    };
-                    return self.update_user(id, update_req).await;
-                }
-                Err(DatabaseError::UserNotFound) => return Err(DatabaseError::UserNotFound),
-                Err(_) if attempt < MAX_RETRIES => {
-                    println!("🔁 Retry attempt {} due to temporary issue", attempt);
-                    sleep(RETRY_DELAY).await;
-                    continue;
-                }
-                Err(e) => return Err(e),
+    #[test]
+    fn country_minimum_age_applies_with_a_default_fallback() {
+        let svc = quiet()
+            .min_age(13)
+            .country_min_ages(HashMap::from([("kr".to_string(), 19)]))
+            .build();
+        let signup = |age: u8, country: Option<&str>| {
+            let mut request = req("Ann", "ann@example.com", age);
+            request.country = country.map(String::from);
+            svc.validate_batch(&[request]).remove(0)
+        };
+        match signup(18, Some("KR")) {
+            Err(DatabaseError::ValidationError(msg)) => {
+                assert_eq!(msg, "Users in KR must be at least 19")
             }
+            other => panic!("expected a validation error, got {other:?}"),
         }
-        Err(DatabaseError::UserNotFound)
+        assert!(signup(19, Some("kr")).is_ok());
+        assert!(signup(13, Some("US")).is_ok());
+        assert!(signup(13, None).is_ok());
+        assert!(signup(12, Some("US")).is_err());
     }
 
-    async fn validate_user_data(&self, req: &CreateUserRequest) -> Result<(), DatabaseError> {
-        sleep(Duration::from_millis(10)).await;
-        if req.name.is_empty() {
-            return Err(DatabaseError::ValidationError(
-                "Name cannot be empty".to_string(),
-            ));
-        }
-        if !req.email.contains('@') {
-            return Err(DatabaseError::ValidationError(
-                "Invalid email format".to_string(),
-            ));
+    #[tokio::test]
+    async fn restores_and_merge_imports_emit_events() {
+        let source = service();
+        let users = seed(&source, 2).await;
+        let backup = temp_path("events.csv");
+        source.bulk_save_to_csv(&backup).await.unwrap();
+
+        let recording = Arc::new(RecordingSink::default());
+        let svc = Arc::new(quiet().event_sink(recording.clone()).build());
+        let mut events = svc.subscribe_events();
+        Arc::clone(&svc)
+            .restore_from_csv(&backup, RestorePolicy::FailIfNotEmpty)
+            .await
+            .unwrap();
+        let mut restored = recording.kinds();
+        restored.sort();
+        let mut expected: Vec<_> = users.iter().map(|u| ("created", u.id.clone())).collect();
+        expected.sort();
+        assert_eq!(restored, expected);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            UserEvent::Created(_)
+        ));
+
+        let mut newer = svc.get_user(&users[0].id).await.unwrap();
+        newer.name = "Newer Name".to_string();
+        newer.updated_at += chrono::Duration::minutes(5);
+        let mut fresh = generated_users(1).remove(0);
+        fresh.email = Some("fresh@example.com".to_string());
+        let mut feed = String::new();
+        for user in [&newer, &fresh] {
+            feed.push_str(&serde_json::to_string(user).unwrap());
+            feed.push('\n');
         }
-        if req.age < 13 || req.age > 120 {
-            return Err(DatabaseError::ValidationError(
-                "Age must be between 13 and 120".to_string(),
-            ));
+        Arc::clone(&svc)
+            .merge_import_ndjson_stream(feed.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            recording.kinds()[2..],
+            [("updated", newer.id.clone()), ("created", fresh.id.clone())]
+        );
+        let _ = std::fs::remove_file(backup);
+    }
+
+    #[tokio::test]
+    async fn folded_search_matches_accents_on_either_side() {
+        let svc = service();
+        let jose = svc
+            .create_user(req("José Núñez", "jose@example.com", 30))
+            .await
+            .unwrap();
+        svc.create_user(req("Bob", "bob@example.com", 30))
+            .await
+            .unwrap();
+        let folded = SearchOptions {
+            fold_diacritics: true,
+        };
+        let ids = |users: Vec<User>| users.into_iter().map(|u| u.id).collect::<Vec<_>>();
+
+        assert!(svc.search_users_parallel("nunez").await.unwrap().is_empty());
+        assert_eq!(
+            ids(svc.search_users_with("NUNEZ", folded).await.unwrap()),
+            vec![jose.id.clone()]
+        );
+        assert_eq!(
+            ids(svc.search_users_with("Josè", folded).await.unwrap()),
+            vec![jose.id.clone()]
+        );
+        assert_eq!(
+            ids(svc.search_users_parallel("JOSÉ").await.unwrap()),
+            vec![jose.id]
+        );
+    }
+
+    #[tokio::test]
+    async fn tracked_bulk_create_reports_the_creates_of_the_run() {
+        let svc = Arc::new(quiet().build());
+        seed(&svc, 3).await;
+        let mut requests: Vec<_> = (0..5)
+            .map(|i| req(&format!("Bulk {i}"), &format!("bulk{i}@example.com"), 30))
+            .collect();
+        requests.push(req("Dup", "seed0@example.com", 30));
+
+        let run = Arc::clone(&svc)
+            .bulk_create_users_tracked(requests)
+            .await
+            .unwrap();
+        let created = run.results.iter().filter(|r| r.is_ok()).count();
+        assert_eq!(created, 5);
+        assert_eq!(run.stats_delta.create_count, created as u64);
+    }
+
+    /// Insert times of `benchmark_reserve` into a growing and a pre-reserved
+    /// map.
+    #[derive(Debug, Clone)]
+    struct ReserveBenchmark {
+        growing: Duration,
+        reserved: Duration,
+    }
+
+    /// Inserts `count` UUID-keyed entries from the rayon pool into a fresh
+    /// map, once letting it grow and once after reserving room for all of them.
+    fn benchmark_reserve(count: usize) -> ReserveBenchmark {
+        let keys: Vec<String> = (0..count)
+            .into_par_iter()
+            .map(|_| Uuid::new_v4().to_string())
+            .collect();
+        let time_inserts = |map: &DashMap<String, usize, TableHasher>| {
+            let start = Instant::now();
+            keys.par_iter().enumerate().for_each(|(i, key)| {
+                map.insert(key.clone(), i);
+            });
+            start.elapsed()
+        };
+        let growing = time_inserts(&new_map(None, MapHasher::default()));
+        let map = new_map(None, MapHasher::default());
+        reserve_map(&map, count);
+        ReserveBenchmark {
+            growing,
+            reserved: time_inserts(&map),
         }
-        Ok(())
     }
 
-    async fn increment_stat<F>(&self, updater: F)
-    where
-        F: FnOnce(&mut ServiceStats),
-    {
-        let mut stats = self.stats.entry(()).or_default();
-        stats.total_operations += 1;
-        updater(&mut stats);
+    #[test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    fn bench_reserve() {
+        let report = benchmark_reserve(1_000_000);
+        println!(
+            "growing {:?}, reserved {:?}",
+            report.growing, report.reserved
+        );
     }
 
-    pub async fn get_stats(&self) -> ServiceStats {
-        self.stats
-            .get(&())
-            .map(|s| s.value().clone())
-            .unwrap_or_default()
+    #[tokio::test]
+    async fn reserve_makes_room_in_the_map_and_indexes() {
+        let svc = quiet().name_index(true).build();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        svc.reserve(1_000);
+
+        let tables = svc.tables.load();
+        assert!(tables.db.capacity() >= 1_001);
+        assert!(tables.email_index.capacity() >= 1_001);
+        assert!(tables.name_index.as_ref().unwrap().capacity() >= 1_001);
+        assert_eq!(svc.get_user(&user.id).await.unwrap().id, user.id);
     }
-}
 
-pub async fn run_demo(service: Arc<UserService>) {
-    println!("🚀 Starting Advanced Rust CRUD Demo with Parallel Processing\n");
-    println!(
-        "🔧 Tokio workers: {}",
-        tokio::runtime::Handle::current().metrics().num_workers()
-    );
-    println!("🔧 Rayon threads: {}", rayon::current_num_threads());
+    #[test]
+    fn log_format_reads_the_flag_in_both_spellings() {
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(LogFormat::from_args(args(&[])), Ok(LogFormat::Pretty));
+        assert_eq!(
+            LogFormat::from_args(args(&["--log-format", "json"])),
+            Ok(LogFormat::Json)
+        );
+        assert_eq!(
+            LogFormat::from_args(args(&["--log-format=pretty"])),
+            Ok(LogFormat::Pretty)
+        );
+        assert!(LogFormat::from_args(args(&["--log-format"])).is_err());
+        assert!(LogFormat::from_args(args(&["--log-format=xml"])).is_err());
+        assert!(LogFormat::from_args(args(&["--verbose"])).is_err());
+    }
 
-    println!("=== 🔧 Basic CRUD ===");
-    let start = Instant::now();
-    let create_req = CreateUserRequest {
-        name: "John Doe".to_string(),
-        email: "john@example.com".to_string(),
-        age: 30,
-    };
+    /// Run in a child process by `json_demo_prints_one_object_per_line`, which
+    /// reads its stdout.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "run by json_demo_prints_one_object_per_line"]
+    async fn json_demo_child() {
+        let svc = Arc::new(
+            UserService::builder()
+                .reporter(LogFormat::Json.reporter())
+                .build(),
+        );
+        run_demo(svc, DemoScale::Small, LogFormat::Json).await;
+    }
 
-    match service.create_user(create_req).await {
-        Ok(user) => {
-            println!("✅ Created: {} [Time: {:?}]", user.name, start.elapsed());
-            let start = Instant::now();
-            match service.get_user(&user.id).await {
-                Ok(found) => println!("✅ Found: {} [Time: {:?}]", found.name, start.elapsed()),
-                Err(e) => println!("❌ Get failed: {} [Time: {:?}]", e, start.elapsed()),
+    #[test]
+    fn json_demo_prints_one_object_per_line() {
+        // The demo writes its export into the working directory.
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = run_child_test("tests::json_demo_child", &dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(!stdout.contains('✅'));
+        let lines: Vec<serde_json::Value> = stdout
+            .lines()
+            // The harness prints the test name ahead of the first line.
+            .filter_map(|line| line.find('{').map(|start| &line[start..]))
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(lines.len() > 10, "{} lines", lines.len());
+        for line in &lines {
+            for key in ["operation", "count", "duration_ms"] {
+                assert!(line.get(key).is_some(), "{line} has no {key}");
             }
-            let start = Instant::now();
-            let update = UpdateUserRequest {
-                name: Some("John Smith".to_string()),
-                email: None,
-                age: Some(31),
-            };
-            match service.update_user(&user.id, update).await {
-                Ok(updated) => {
-                    println!("✅ Updated: {} [Time: {:?}]", updated.name, start.elapsed())
+        }
+        assert_eq!(lines[0]["operation"], "demo_started");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_replaces_and_updates_do_not_deadlock() {
+        let svc = service();
+        let users = seed(&svc, 4).await;
+        let mut tasks = tokio::task::JoinSet::new();
+        for round in 0..200 {
+            let svc = Arc::clone(&svc);
+            let id = users[round % users.len()].id.clone();
+            tasks.spawn(async move {
+                match round % 3 {
+                    0 => {
+                        let name = format!("Replaced {round}");
+                        let email = format!("replaced{round}@example.com");
+                        svc.replace_user(&id, req(&name, &email, 40))
+                            .await
+                            .map(|_| ())
+                    }
+                    1 => svc
+                        .update_user(&id, name_update(&format!("Updated {round}")))
+                        .await
+                        .map(|_| ()),
+                    _ => svc.get_user(&id).await.map(|_| ()),
                 }
-                Err(e) => println!("❌ Update failed: {} [Time: {:?}]", e, start.elapsed()),
+            });
+        }
+        let all = async {
+            while let Some(result) = tasks.join_next().await {
+                result.unwrap().unwrap();
             }
+        };
+        tokio::time::timeout(Duration::from_secs(10), all)
+            .await
+            .expect("tasks deadlocked");
+
+        for user in &users {
+            let stored = svc.get_user(&user.id).await.unwrap();
+            let email = stored.email.as_deref().unwrap();
+            assert_eq!(svc.get_user_by_email(email).await.unwrap().id, user.id);
         }
-        Err(e) => println!("❌ Create failed: {} [Time: {:?}]", e, start.elapsed()),
     }
 
-    println!(
-        "\n=== 🔥 BULK: 10.000.000 USERS (Rayon(CPU Abound) + Tokio(IO Abound) + DashMap) ==="
-    );
-    let bulk_req: Vec<_> = (0..10_000_000)
-        .map(|i| CreateUserRequest {
-            name: format!("BulkUser{}", i),
-            email: format!("user{}@bulk.com", i),
-            age: 20 + (i % 80) as u8,
-        })
-        .collect();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bulk_calls_over_the_limit_wait_for_a_permit() {
+        let source = service();
+        seed(&source, 3).await;
+        let dir = temp_path("bulk-permits");
+        source.save_snapshot(&dir).await.unwrap();
+        let backup = temp_path("bulk-permits.csv");
+        source.bulk_save_to_csv(&backup).await.unwrap();
+        let feed = generated_users(2)
+            .iter()
+            .map(|user| serde_json::to_string(user).unwrap() + "\n")
+            .collect::<String>();
 
-    let start = Instant::now();
-    let results = service.clone().bulk_create_users(bulk_req).await;
-    let success = results.iter().filter(|r| r.is_ok()).count();
-    println!(
-        "✅ Bulk done in {:?} | {} success",
-        start.elapsed(),
-        success
-    );
+        let svc = Arc::new(quiet().max_concurrent_bulk(1).build());
+        let held = svc.bulk_permit().await;
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..4 {
+            let svc = Arc::clone(&svc);
+            let (dir, backup, feed) = (dir.clone(), backup.clone(), feed.clone());
+            tasks.spawn(async move {
+                match i {
+                    0 => {
+                        let requests = vec![req("Bulk", "bulk@example.com", 30)];
+                        svc.bulk_create_users(requests).await.map(|_| ()).unwrap()
+                    }
+                    1 => svc
+                        .restore_from_csv(&backup, RestorePolicy::SkipExisting)
+                        .await
+                        .map(|_| ())
+                        .unwrap(),
+                    2 => svc
+                        .merge_import_ndjson_stream(feed.as_bytes())
+                        .await
+                        .map(|_| ())
+                        .unwrap(),
+                    _ => svc.load_latest_snapshot(&dir).await.map(|_| ()).unwrap(),
+                }
+            });
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(svc.list_users().await.unwrap().is_empty());
+        assert_eq!(tasks.len(), 4);
 
-    println!("\n=== ⚡ FAST Concurrent Tasks (5) ===");
-    let start = Instant::now();
-    let _ = service.clone().fast_concurrent_operations().await;
-    println!("✅ Fast concurrent ops done in {:?}", start.elapsed());
+        drop(held);
+        let all = async {
+            while let Some(result) = tasks.join_next().await {
+                result.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), all)
+            .await
+            .expect("a bulk call never got a permit");
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_file(&backup);
+        assert_eq!(svc.bulk_permits.available_permits(), 1);
+        assert!(!svc.list_users().await.unwrap().is_empty());
+    }
 
-    println!("\n=== 🚀 BULK Concurrent Insert (5000) ===");
-    let start = Instant::now();
-    let _ = service.clone().bulk_insert_concurrent(5000).await;
-    println!("✅ Bulk concurrent insert done in {:?}", start.elapsed());
+    #[tokio::test]
+    async fn search_cache_serves_hits_until_expiry_or_a_mutation() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet()
+            .clock(clock.clone())
+            .search_cache(8, Duration::from_secs(30))
+            .build();
+        svc.create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let count = |users: Vec<User>| users.len();
+        assert_eq!(count(svc.search_users_parallel("ANN").await.unwrap()), 1);
 
-    println!("\n=== 📊 Final Stats ===");
-    let stats = service.get_stats().await;
-    println!("Total ops: {}", stats.total_operations);
-    println!(
-        "Creates: {}, Reads: {}, Updates: {}, Deletes: {}",
-        stats.create_count, stats.read_count, stats.update_count, stats.delete_count
-    );
-    println!("Parallel batches: {}", stats.parallel_operations);
+        // Stored behind the service's back, so only a scan can find it.
+        let mut hidden = generated_users(1).remove(0);
+        hidden.name = "Annie".to_string();
+        svc.tables.load().db.insert(hidden.id.clone(), hidden);
+        assert_eq!(count(svc.search_users_parallel("ann").await.unwrap()), 1);
 
-    println!("\n=== 💾 SAVE TO CSV ===");
-    let csv_path = "users_export.csv";
-    if let Err(e) = service.bulk_save_to_csv(csv_path).await {
-        eprintln!("❌ Save to CSV failed: {}", e);
+        clock.advance(chrono::Duration::seconds(31));
+        assert_eq!(count(svc.search_users_parallel("ann").await.unwrap()), 2);
+
+        svc.create_user(req("Anna", "anna@example.com", 30))
+            .await
+            .unwrap();
+        assert_eq!(count(svc.search_users_parallel("ann").await.unwrap()), 3);
     }
 
-    println!("\n=== 📥 LOAD FROM CSV ===");
-    if let Err(e) = service.clone().bulk_load_from_csv(csv_path).await {
-        eprintln!("❌ Load from CSV failed: {}", e);
+    #[tokio::test]
+    async fn stats_history_csv_has_one_row_per_sample() {
+        let clock = Arc::new(MockClock::new(at("2024-01-01T00:00:00Z")));
+        let svc = quiet().clock(clock.clone()).build();
+        for i in 0..3 {
+            svc.create_user(req(
+                &format!("user {i}"),
+                &format!("user{i}@example.com"),
+                30,
+            ))
+            .await
+            .unwrap();
+            svc.sample_stats().await;
+            clock.advance(chrono::Duration::seconds(10));
+        }
+        let path = temp_path("stats-history.csv");
+        assert_eq!(svc.export_stats_history_csv(&path).await.unwrap(), 3);
+
+        let mut rdr = csv::Reader::from_path(&path).unwrap();
+        let headers = rdr.headers().unwrap().clone();
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        let rows: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(rows.len(), 3);
+        let creates: Vec<&str> = rows.iter().map(|r| &r[column("create_count")]).collect();
+        assert_eq!(creates, ["1", "2", "3"]);
+        assert_eq!(&rows[2][column("timestamp")], "2024-01-01T00:00:20+00:00");
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let service = Arc::new(UserService::new());
-    run_demo(service).await;
-    Ok(())
+    #[tokio::test]
+    async fn empty_bulk_input_is_a_silent_no_op() {
+        let reporter = Arc::new(CollectingReporter::default());
+        let svc = Arc::new(UserService::builder().reporter(reporter.clone()).build());
+        let before = svc.get_stats().await;
+
+        assert!(
+            Arc::clone(&svc)
+                .bulk_create_users(Vec::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let summary = Arc::clone(&svc).bulk_create_from_iter(Vec::new()).await;
+        assert_eq!((summary.created, summary.failed), (0, 0));
+        Arc::clone(&svc).bulk_insert_concurrent(0).await.unwrap();
+
+        assert!(reporter.events().is_empty());
+        assert_eq!(svc.stats_delta(&before).total_operations, 0);
+    }
+
+    #[tokio::test]
+    async fn clean_import_counts_each_stage() {
+        let svc = service();
+        svc.create_user(req("Taken", "taken@example.com", 30))
+            .await
+            .unwrap();
+        let path = temp_path("messy.csv");
+        std::fs::write(
+            &path,
+            "name,email,age\n\
+             First,first@example.com,30\n\
+             Again,FIRST@example.com,31\n\
+             Young,young@example.com,5\n\
+             Bad,not-an-email,30\n\
+             Clash,taken@example.com,30\n\
+             Second,second@example.com,40\n",
+        )
+        .unwrap();
+
+        let report = Arc::clone(&svc).import_csv_clean(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            (
+                report.parsed,
+                report.deduped,
+                report.invalid,
+                report.inserted,
+                report.failed
+            ),
+            (6, 1, 2, 2, 1)
+        );
+        assert_eq!(
+            svc.get_user_by_email("first@example.com")
+                .await
+                .unwrap()
+                .age,
+            Some(30)
+        );
+        assert_eq!(svc.list_users().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn prefixed_ids_are_retrievable_and_survive_export_and_restore() {
+        let prefixed = || Arc::new(quiet().id_prefix("usr_").build());
+        let svc = prefixed();
+        let user = svc
+            .create_user(req("Ann", "ann@example.com", 30))
+            .await
+            .unwrap();
+        let results = Arc::clone(&svc)
+            .bulk_create_users(vec![req("Bob", "bob@example.com", 30)])
+            .await
+            .unwrap();
+        let bulk = results[0].as_ref().unwrap();
+        for id in [&user.id, &bulk.id] {
+            assert!(id.starts_with("usr_"), "{id}");
+            assert!(Uuid::parse_str(&id["usr_".len()..]).is_ok());
+            assert_eq!(&svc.get_user(id).await.unwrap().id, id);
+        }
+
+        let path = temp_path("prefixed.csv");
+        svc.bulk_save_to_csv(&path).await.unwrap();
+        let restored = prefixed();
+        Arc::clone(&restored)
+            .restore_from_csv(&path, RestorePolicy::FailIfNotEmpty)
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(restored.get_user(&user.id).await.unwrap().name, "Ann");
+        assert_eq!(restored.get_user(&bulk.id).await.unwrap().id, bulk.id);
+    }
+
+    #[test]
+    fn bulk_work_completes_on_a_one_thread_pool() {
+        let svc = quiet().build();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let requests: Vec<_> = (0..2_000)
+            .map(|i| req(&format!("User {i}"), &format!("user{i}@example.com"), 30))
+            .chain([req("Young", "young@example.com", 5)])
+            .collect();
+        let users = generated_users(2_000);
+
+        let (valid, stored, invalid, hash) = pool.install(|| {
+            assert_eq!(rayon::current_num_threads(), 1);
+            let valid = svc
+                .validate_batch(&requests)
+                .iter()
+                .filter(|r| r.is_ok())
+                .count();
+            let stored = svc.replace_dataset(users).unwrap();
+            (valid, stored, svc.find_invalid().len(), svc.dataset_hash())
+        });
+        assert_eq!((valid, stored, invalid), (2_000, 2_000, 0));
+        assert_eq!(hash, svc.dataset_hash());
+    }
+
+    #[tokio::test]
+    async fn merge_import_keeps_the_newest_line_for_a_new_id_within_a_batch() {
+        let mut created = generated_users(1).remove(0);
+        created.email = Some("first@example.com".to_string());
+        let mut updated = created.clone();
+        updated.name = "Updated Name".to_string();
+        updated.email = Some("second@example.com".to_string());
+        updated.updated_at = created.updated_at + chrono::Duration::minutes(5);
+
+        for order in [[&created, &updated], [&updated, &created]] {
+            let svc = service();
+            let feed: String = order
+                .iter()
+                .map(|user| serde_json::to_string(user).unwrap() + "\n")
+                .collect();
+            let summary = Arc::clone(&svc)
+                .merge_import_ndjson_stream(feed.as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(
+                (summary.created, summary.updated, summary.skipped),
+                (1, 0, 1)
+            );
+            assert_eq!(
+                svc.get_user(&created.id).await.unwrap().name,
+                "Updated Name"
+            );
+            assert_eq!(
+                svc.get_user_by_email("second@example.com")
+                    .await
+                    .unwrap()
+                    .id,
+                created.id
+            );
+            assert!(svc.get_user_by_email("first@example.com").await.is_err());
+            assert!(matches!(svc.self_check().await, HealthStatus::Healthy));
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_import_reports_each_failed_line_with_its_number() {
+        let reporter = Arc::new(CollectingReporter::default());
+        let svc = Arc::new(UserService::builder().reporter(reporter.clone()).build());
+        let valid = generated_users(1).remove(0);
+        let mut young = generated_users(2).remove(1);
+        young.age = Some(5);
+        let feed = format!(
+            "{}\n\n{{not json\n{}\n",
+            serde_json::to_string(&valid).unwrap(),
+            serde_json::to_string(&young).unwrap()
+        );
+
+        let summary = Arc::clone(&svc)
+            .merge_import_ndjson_stream(feed.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!((summary.created, summary.failed), (1, 2));
+        let failures: Vec<(usize, String)> = reporter
+            .events()
+            .into_iter()
+            .filter_map(|event| match event {
+                ProgressEvent::MergeLineFailed { line, reason } => Some((line, reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].0, 3);
+        assert!(
+            failures[0].1.contains("Malformed record"),
+            "{}",
+            failures[0].1
+        );
+        assert_eq!(failures[1].0, 4);
+        assert!(failures[1].1.contains("Age"), "{}", failures[1].1);
+    }
+
+    #[tokio::test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    async fn bench_columnar_average_age() {
+        const ROUNDS: u32 = 20;
+        let svc = quiet().build();
+        let mut users = generated_users(1_000_000);
+        for user in users.iter_mut().step_by(7) {
+            user.age = None;
+        }
+        svc.replace_dataset(users).unwrap();
+        let snapshot = svc.snapshot_columns().await;
+
+        // Both sides scan in parallel and average over known ages only.
+        let tables = svc.tables.load_full();
+        let map_average = || {
+            let (total, known) = tables
+                .db
+                .par_iter()
+                .filter_map(|kv| kv.value().age)
+                .map(|age| (age as u64, 1u64))
+                .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+            total as f64 / known as f64
+        };
+        let start = Instant::now();
+        let mut on_map = 0.0;
+        for _ in 0..ROUNDS {
+            on_map = map_average();
+        }
+        let map_time = start.elapsed() / ROUNDS;
+        let start = Instant::now();
+        let mut on_snapshot = 0.0;
+        for _ in 0..ROUNDS {
+            on_snapshot = snapshot.average_age().unwrap();
+        }
+        let snapshot_time = start.elapsed() / ROUNDS;
+
+        assert!((on_map - on_snapshot).abs() < 1e-9);
+        println!(
+            "average age {:.2}: map {:?}, snapshot {:?} per scan",
+            on_snapshot, map_time, snapshot_time
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stats_logger_stops_once_the_service_is_dropped() {
+        let svc = service();
+        let path = temp_path("stats-drop.ndjson");
+        let logger = Arc::clone(&svc).spawn_stats_logger(&path, Duration::from_millis(50));
+        sleep(Duration::from_millis(120)).await;
+        drop(svc);
+        tokio::time::timeout(Duration::from_secs(2), logger)
+            .await
+            .expect("logger should exit after the service is dropped")
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
 }